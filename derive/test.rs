@@ -1,23 +1,337 @@
 #[macro_use] extern crate heapsize_derive;
+extern crate heapsize;
 
-mod heapsize {
-    pub trait HeapSizeOf {
-        fn heap_size_of_children(&self) -> usize;
-    }
+use heapsize::{HeapSize, HeapSizeOf, Reporter, SizeClassModel, SizeOps};
+use std::marker::PhantomData;
+use std::mem::size_of;
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::Arc;
+
+// Count every heap block as one byte, so that results don't depend on the allocator.
+unsafe fn one_per_block(_: *const c_void) -> usize {
+    1
+}
+
+#[derive(HeapSizeOf)]
+struct Foo([Box<u32>; 2], Box<u8>);
+
+#[derive(HeapSizeOf)]
+struct Entry {
+    key: String,
+    value: Box<u64>,
+}
+
+#[derive(HeapSizeOf)]
+struct Cache {
+    entries: Vec<Entry>,
+    #[ignore_heap_size_of = "not ours"]
+    #[allow(dead_code)]
+    spare: Box<u64>,
+}
+
+#[test]
+fn test() {
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    let x = Foo([Box::new(1), Box::new(2)], Box::new(3));
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 3);
+}
+
+#[test]
+fn test_report() {
+    let cache = Cache {
+        entries: vec![
+            Entry { key: "a".to_owned(), value: Box::new(1) },
+            Entry { key: "b".to_owned(), value: Box::new(2) },
+        ],
+        spare: Box::new(3),
+    };
+
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    cache.report_heap_size("cache", &mut reporter);
+    let report = reporter.finish();
+
+    // `Vec` reports its buffer at its own path, and the fields of its elements below it.
+    assert_eq!(report.get("cache/entries").unwrap().size(), 5);
+    assert_eq!(report.get("cache/entries").unwrap().own_size(), 1);
+    assert_eq!(report.get("cache/entries/key").unwrap().size(), 2);
+    assert_eq!(report.get("cache/entries/value").unwrap().size(), 2);
+    assert!(report.get("cache/spare").is_none());
+    assert_eq!(report.size(), 5);
+
+    let x = Foo([Box::new(1), Box::new(2)], Box::new(3));
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    x.report_heap_size("", &mut reporter);
+    let report = reporter.finish();
+    assert_eq!(report.get("0").unwrap().size(), 2);
+    assert_eq!(report.get("1").unwrap().size(), 1);
+}
+
+#[test]
+fn test_slop() {
+    let cache = Cache {
+        entries: vec![Entry { key: "a".to_owned(), value: Box::new(1) }],
+        spare: Box::new(3),
+    };
+
+    // The key's single byte is rounded up to 8; the entries and the value are allocated exactly.
+    let mut ops = SizeOps::estimate_with_model(SizeClassModel::Jemalloc);
+    let entry_size = std::mem::size_of::<Entry>();
+    assert_eq!(ops.measure(&cache), HeapSize { requested: entry_size + 1 + 8, slop: 7, spare: 0 });
+}
+
+#[derive(HeapSizeOf)]
+struct Grid<T> {
+    cells: [[T; 2]; 3],
+}
+
+#[test]
+fn test_nested_arrays() {
+    let grid = Grid { cells: [[0u8; 2]; 3].map(|row| row.map(Box::new)) };
+    assert_eq!(grid.heap_size_of_children_with(&mut SizeOps::with_size_of_op(one_per_block)), 6);
+}
+
+// Stands in for a type from another crate, which can't implement `HeapSizeOf`.
+struct Foreign(Vec<u8>);
+
+fn foreign_size(x: &Foreign) -> usize {
+    x.0.capacity()
+}
+
+const HEADER_SIZE: usize = 8;
+
+#[derive(HeapSizeOf)]
+struct Wrapper {
+    #[heap_size_of(with = "foreign_size")]
+    data: Foreign,
+    #[heap_size_of(size = 64)]
+    #[allow(dead_code)]
+    buffer: usize,
+    #[heap_size_of(size = "2 * HEADER_SIZE")]
+    #[allow(dead_code)]
+    headers: (),
+}
+
+#[test]
+fn test_custom_size() {
+    let x = Wrapper { data: Foreign(Vec::with_capacity(10)), buffer: 0, headers: () };
+    assert_eq!(x.heap_size_of_children(), 10 + 64 + 16);
+
+    let mut reporter = Reporter::new();
+    x.report_heap_size("", &mut reporter);
+    let report = reporter.finish();
+    assert_eq!(report.get("data").unwrap().size(), 10);
+    assert_eq!(report.get("buffer").unwrap().size(), 64);
+    assert_eq!(report.get("headers").unwrap().size(), 16);
+}
+
+#[derive(HeapSizeOf)]
+struct Config {
+    name: String,
+}
+
+#[derive(HeapSizeOf)]
+struct Instance {
+    #[heap_size_of(shared)]
+    config: Arc<Config>,
+    #[heap_size_of(owned)]
+    state: Rc<Vec<u64>>,
+    #[allow(dead_code)]
+    parent: Rc<Vec<u64>>,
+}
+
+#[test]
+fn test_shared_fields() {
+    let config = Arc::new(Config { name: "abc".to_owned() });
+    let instances: Vec<_> = (0..3).map(|i| Instance {
+        config: config.clone(),
+        state: Rc::new(vec![i]),
+        parent: Rc::new(vec![i]),
+    }).collect();
 
-    impl<T> HeapSizeOf for Box<T> {
-        fn heap_size_of_children(&self) -> usize {
-            ::std::mem::size_of::<T>()
+    // The config is counted once for all instances, along with its name. Each state is counted
+    // with its block, and the parents only with what hangs off them.
+    let rc_block = 2 * size_of::<usize>() + size_of::<Vec<u64>>();
+    let expected = size_of::<Instance>() * 3 + (2 * size_of::<usize>() + size_of::<Config>() + 3) +
+        3 * (rc_block + 8) + 3 * 8;
+    assert_eq!(instances.heap_size_of_children_with(&mut SizeOps::estimate()), expected);
+    assert_eq!(SizeOps::estimate().shared_policy(), heapsize::SharedPolicy::Skip);
+
+    let mut reporter = Reporter::with_ops(SizeOps::estimate());
+    instances.report_heap_size("instances", &mut reporter);
+    assert_eq!(reporter.ops().shared_policy(), heapsize::SharedPolicy::Skip);
+    assert_eq!(reporter.finish().size(), expected);
+}
+
+#[derive(HeapSizeOf)]
+struct Bounded<'a, 'b: 'a, T: 'a, const N: usize>
+    where T: Clone
+{
+    r#type: [T; N],
+    borrowed: &'a &'b str,
+}
+
+// Input that comes out of another macro.
+macro_rules! generated {
+    ($name:ident, $ty:ty) => {
+        #[derive(HeapSizeOf)]
+        struct $name {
+            field: $ty,
+        }
+    };
+}
+
+generated!(Generated, Vec<Box<u8>>);
+
+#[derive(HeapSizeOf)]
+enum Either<T> {
+    Left(T),
+    Right { r#box: Box<u8> },
+}
+
+#[test]
+fn test_generics() {
+    let borrowed = &"abc";
+    let x = Bounded { r#type: [Box::new(1u8), Box::new(2)], borrowed };
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 2);
+
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    x.report_heap_size("", &mut reporter);
+    assert_eq!(reporter.finish().get("type").unwrap().size(), 2);
+
+    let x = Generated { field: vec![Box::new(0)] };
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::with_size_of_op(one_per_block)), 2);
+
+    let x: Either<Vec<u8>> = Either::Right { r#box: Box::new(0) };
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    x.report_heap_size("", &mut reporter);
+    assert_eq!(reporter.finish().get("box").unwrap().size(), 1);
+    assert_eq!(Either::Left(vec![1u8]).heap_size_of_children_with(
+        &mut SizeOps::with_size_of_op(one_per_block)), 1);
+}
+
+// Implements nothing, so can only appear where it doesn't need measuring.
+struct Opaque;
+
+#[derive(HeapSizeOf)]
+struct Handle<T> {
+    #[allow(dead_code)]
+    id: u32,
+    _marker: PhantomData<T>,
+}
+
+#[derive(HeapSizeOf)]
+struct View<'a, T: 'a> {
+    items: Vec<String>,
+    #[allow(dead_code)]
+    target: &'a T,
+    #[ignore_heap_size_of = "owned elsewhere"]
+    #[allow(dead_code)]
+    owner: Box<T>,
+}
+
+trait Payload {
+    type Data;
+}
+
+impl Payload for Opaque {
+    type Data = Vec<u8>;
+}
+
+// Only `T::Data` is measured, which the field bounds can't see through the associated type.
+#[derive(HeapSizeOf)]
+#[heap_size_of(bound = "T: Payload, T::Data: heapsize::HeapSizeOf")]
+struct Message<T: Payload> {
+    data: T::Data,
+}
+
+#[test]
+fn test_bounds() {
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    let x = Handle::<Opaque> { id: 0, _marker: PhantomData };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 0);
+    let x = View { items: vec![String::from("a")], target: &Opaque, owner: Box::new(Opaque) };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 2);
+    let x = Message::<Opaque> { data: vec![0] };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 1);
+}
+
+// A C-style buffer whose memory it owns.
+#[derive(HeapSizeOf)]
+#[repr(C)]
+struct RawBuffer {
+    #[heap_size_of(owns_ptr)]
+    data: *mut u64,
+    #[allow(dead_code)]
+    len: usize,
+}
+
+impl Drop for RawBuffer {
+    fn drop(&mut self) {
+        if !self.data.is_null() {
+            unsafe { drop(Box::from_raw(self.data)) };
         }
     }
 }
 
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Inline {
+    tag: u8,
+    bytes: [u8; 15],
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct Boxed {
+    tag: u8,
+    ptr: *mut u64,
+}
 
+// Tells apart its variants by the tag both of them start with.
 #[derive(HeapSizeOf)]
-struct Foo([Box<u32>; 2], Box<u8>);
+#[heap_size_of(union_with = "small_string_size")]
+union SmallString {
+    inline: Inline,
+    boxed: Boxed,
+}
+
+fn small_string_size(x: &SmallString) -> usize {
+    unsafe {
+        match x.inline.tag {
+            0 => 0,
+            _ => heapsize::heap_size_of(x.boxed.ptr),
+        }
+    }
+}
 
 #[test]
-fn test() {
-    use heapsize::HeapSizeOf;
-    assert_eq!(Foo([Box::new(1), Box::new(2)], Box::new(3)).heap_size_of_children(), 9);
+fn test_ffi() {
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    let x = RawBuffer { data: Box::into_raw(Box::new(0)), len: 1 };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 1);
+    assert_eq!(x.heap_size_of_children(), unsafe { heapsize::heap_size_of(x.data) });
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    x.report_heap_size("", &mut reporter);
+    assert_eq!(reporter.finish().get("data").unwrap().size(), 1);
+    let x = RawBuffer { data: std::ptr::null_mut(), len: 0 };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 0);
+
+    // Estimates go by the layout of the pointee, without asking the allocator.
+    let mut ops = SizeOps::estimate_with_model(SizeClassModel::Jemalloc);
+    let x = RawBuffer { data: Box::into_raw(Box::new(0)), len: 1 };
+    assert_eq!(ops.measure(&x), HeapSize { requested: 8, slop: 0, spare: 0 });
+    let mut reporter = Reporter::with_ops(SizeOps::estimate());
+    x.report_heap_size("", &mut reporter);
+    assert_eq!(reporter.finish().get("data").unwrap().size(), 8);
+    let x = RawBuffer { data: std::ptr::null_mut(), len: 0 };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 0);
+
+    let x = SmallString { inline: Inline { tag: 0, bytes: [0; 15] } };
+    assert_eq!(x.heap_size_of_children(), 0);
+    let ptr = Box::into_raw(Box::new(0u64));
+    let x = SmallString { boxed: Boxed { tag: 1, ptr } };
+    assert_eq!(x.heap_size_of_children(), unsafe { heapsize::heap_size_of(ptr) });
+    unsafe { drop(Box::from_raw(ptr)) };
 }
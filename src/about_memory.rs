@@ -0,0 +1,476 @@
+//! Reports in the JSON format loaded by Firefox's about:memory.
+//!
+//! A file holds a list of reports, each of which gives an amount for one path in one process:
+//!
+//! ```text
+//! {
+//!   "version": 1,
+//!   "hasMozMallocUsableSize": true,
+//!   "reports": [
+//!     {"process": "Main Process", "path": "explicit/cache/entries", "kind": 1, "units": 0,
+//!      "amount": 4096, "description": "Heap memory measured at cache/entries."}
+//!   ]
+//! }
+//! ```
+//!
+//! about:memory also loads gzipped files (`memory-report.json.gz`); compress the output of
+//! `MemoryReports::write_json` with whatever gzip implementation is at hand.
+
+use std::char;
+use std::error::Error;
+use std::fmt;
+use std::io::{self, Write};
+
+use MemoryReport;
+
+/// What kind of memory a report is about.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Kind {
+    /// Memory not allocated on the heap, e.g. mapped files.
+    NonHeap,
+    /// Heap-allocated memory.
+    Heap,
+    /// Anything else; the amount need not be memory at all.
+    Other,
+}
+
+impl Kind {
+    fn to_json(self) -> u32 {
+        match self {
+            Kind::NonHeap => 0,
+            Kind::Heap => 1,
+            Kind::Other => 2,
+        }
+    }
+
+    fn from_json(value: i64) -> Option<Kind> {
+        match value {
+            0 => Some(Kind::NonHeap),
+            1 => Some(Kind::Heap),
+            2 => Some(Kind::Other),
+            _ => None,
+        }
+    }
+}
+
+/// The units of a report's amount.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Units {
+    Bytes,
+    Count,
+    CountCumulative,
+    /// Hundredths of a percent.
+    Percentage,
+}
+
+impl Units {
+    fn to_json(self) -> u32 {
+        match self {
+            Units::Bytes => 0,
+            Units::Count => 1,
+            Units::CountCumulative => 2,
+            Units::Percentage => 3,
+        }
+    }
+
+    fn from_json(value: i64) -> Option<Units> {
+        match value {
+            0 => Some(Units::Bytes),
+            1 => Some(Units::Count),
+            2 => Some(Units::CountCumulative),
+            3 => Some(Units::Percentage),
+            _ => None,
+        }
+    }
+}
+
+/// A single measurement.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Report {
+    pub process: String,
+    /// `/`-separated; about:memory shows reports under `explicit/` as a tree.
+    pub path: String,
+    pub kind: Kind,
+    pub units: Units,
+    pub amount: i64,
+    pub description: String,
+}
+
+/// The contents of a report file.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MemoryReports {
+    pub version: u32,
+    /// Whether heap amounts are usable sizes rather than requested sizes.
+    pub has_moz_malloc_usable_size: bool,
+    pub reports: Vec<Report>,
+}
+
+impl MemoryReports {
+    /// An empty file in the current version of the format.
+    pub fn new() -> MemoryReports {
+        MemoryReports {
+            version: 1,
+            has_moz_malloc_usable_size: true,
+            reports: vec![],
+        }
+    }
+
+    /// Add a report for every node of `report` that has an amount of its own, under `prefix`
+    /// (usually `explicit`).
+    ///
+    /// Nodes of the tree that have both an amount and children are reported as a child called
+    /// `own`, because about:memory requires amounts to be at the leaves.
+    pub fn add_tree(&mut self, process: &str, prefix: &str, report: &MemoryReport) {
+        self.add_node(process, prefix.trim_end_matches('/'), report);
+    }
+
+    fn add_node(&mut self, process: &str, path: &str, node: &MemoryReport) {
+        if node.children().is_empty() || node.own_size() > 0 {
+            let path = if node.children().is_empty() {
+                path.to_owned()
+            } else {
+                format!("{}/own", path)
+            };
+            let description = format!("Heap memory measured at {}.", path);
+            self.reports.push(Report {
+                process: process.to_owned(),
+                path,
+                kind: Kind::Heap,
+                units: Units::Bytes,
+                amount: node.own_size() as i64,
+                description,
+            });
+        }
+        for child in node.children() {
+            self.add_node(process, &format!("{}/{}", path, child.name()), child);
+        }
+    }
+
+    /// Write the reports as JSON.
+    pub fn write_json<W: Write>(&self, writer: &mut W) -> io::Result<()> {
+        write!(writer, "{{\"version\":{},\"hasMozMallocUsableSize\":{},\"reports\":[",
+               self.version, self.has_moz_malloc_usable_size)?;
+        for (i, report) in self.reports.iter().enumerate() {
+            if i > 0 {
+                writer.write_all(b",")?;
+            }
+            writer.write_all(b"\n{\"process\":")?;
+            write_string(writer, &report.process)?;
+            writer.write_all(b",\"path\":")?;
+            write_string(writer, &report.path)?;
+            write!(writer, ",\"kind\":{},\"units\":{},\"amount\":{},\"description\":",
+                   report.kind.to_json(), report.units.to_json(), report.amount)?;
+            write_string(writer, &report.description)?;
+            writer.write_all(b"}")?;
+        }
+        writer.write_all(b"\n]}\n")
+    }
+
+    /// The reports as a JSON string.
+    pub fn to_json(&self) -> String {
+        let mut json = vec![];
+        self.write_json(&mut json).unwrap();
+        String::from_utf8(json).unwrap()
+    }
+
+    /// Read reports from JSON, as written by `write_json` or by Firefox.
+    pub fn from_json(json: &str) -> Result<MemoryReports, ParseError> {
+        let mut parser = Parser { json, pos: 0, depth: 0 };
+        let value = parser.parse_value()?;
+        parser.skip_whitespace();
+        if parser.pos != json.len() {
+            return Err(parser.error("trailing characters"));
+        }
+
+        let top = value.as_object().ok_or_else(|| ParseError::new("expected an object", 0))?;
+        let reports = get(top, "reports")?
+            .as_array()
+            .ok_or_else(|| ParseError::new("`reports` should be an array", 0))?;
+        Ok(MemoryReports {
+            version: get_integer(top, "version")? as u32,
+            has_moz_malloc_usable_size: match *get(top, "hasMozMallocUsableSize")? {
+                Value::Bool(b) => b,
+                _ => return Err(ParseError::new("`hasMozMallocUsableSize` should be a bool", 0)),
+            },
+            reports: reports.iter().map(report_from_json).collect::<Result<_, _>>()?,
+        })
+    }
+}
+
+impl Default for MemoryReports {
+    fn default() -> MemoryReports {
+        MemoryReports::new()
+    }
+}
+
+fn write_string<W: Write>(writer: &mut W, s: &str) -> io::Result<()> {
+    writer.write_all(b"\"")?;
+    for c in s.chars() {
+        match c {
+            '"' => writer.write_all(b"\\\"")?,
+            '\\' => writer.write_all(b"\\\\")?,
+            '\n' => writer.write_all(b"\\n")?,
+            '\r' => writer.write_all(b"\\r")?,
+            '\t' => writer.write_all(b"\\t")?,
+            c if (c as u32) < 0x20 => write!(writer, "\\u{:04x}", c as u32)?,
+            c => write!(writer, "{}", c)?,
+        }
+    }
+    writer.write_all(b"\"")
+}
+
+/// The error returned when JSON can't be read as reports.
+#[derive(Debug, PartialEq, Eq)]
+pub struct ParseError {
+    message: String,
+    /// The byte offset in the input at which the problem was found.
+    pub offset: usize,
+}
+
+impl ParseError {
+    fn new(message: &str, offset: usize) -> ParseError {
+        ParseError { message: message.to_owned(), offset }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} at offset {}", self.message, self.offset)
+    }
+}
+
+impl Error for ParseError {}
+
+// Just enough of JSON to read report files.
+enum Value {
+    Null,
+    Bool(bool),
+    Number(f64),
+    String(String),
+    Array(Vec<Value>),
+    Object(Vec<(String, Value)>),
+}
+
+impl Value {
+    fn as_object(&self) -> Option<&[(String, Value)]> {
+        match *self {
+            Value::Object(ref members) => Some(members),
+            _ => None,
+        }
+    }
+
+    fn as_array(&self) -> Option<&[Value]> {
+        match *self {
+            Value::Array(ref elements) => Some(elements),
+            _ => None,
+        }
+    }
+}
+
+fn get<'a>(object: &'a [(String, Value)], key: &str) -> Result<&'a Value, ParseError> {
+    object.iter()
+        .find(|&(k, _)| k == key)
+        .map(|(_, v)| v)
+        .ok_or_else(|| ParseError::new(&format!("missing `{}`", key), 0))
+}
+
+fn get_integer(object: &[(String, Value)], key: &str) -> Result<i64, ParseError> {
+    match *get(object, key)? {
+        Value::Number(n) if n.fract() == 0.0 => Ok(n as i64),
+        _ => Err(ParseError::new(&format!("`{}` should be an integer", key), 0)),
+    }
+}
+
+fn get_string(object: &[(String, Value)], key: &str) -> Result<String, ParseError> {
+    match *get(object, key)? {
+        Value::String(ref s) => Ok(s.clone()),
+        _ => Err(ParseError::new(&format!("`{}` should be a string", key), 0)),
+    }
+}
+
+fn report_from_json(value: &Value) -> Result<Report, ParseError> {
+    let report = value.as_object().ok_or_else(|| ParseError::new("expected a report", 0))?;
+    Ok(Report {
+        process: get_string(report, "process")?,
+        path: get_string(report, "path")?,
+        kind: Kind::from_json(get_integer(report, "kind")?)
+            .ok_or_else(|| ParseError::new("unknown `kind`", 0))?,
+        units: Units::from_json(get_integer(report, "units")?)
+            .ok_or_else(|| ParseError::new("unknown `units`", 0))?,
+        amount: get_integer(report, "amount")?,
+        description: get_string(report, "description")?,
+    })
+}
+
+// How deeply arrays and objects may nest. Reports only go three deep, and this keeps the
+// recursive parser well clear of overflowing the stack.
+const MAX_DEPTH: usize = 128;
+
+struct Parser<'a> {
+    json: &'a str,
+    pos: usize,
+    // The number of arrays and objects we're inside.
+    depth: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn error(&self, message: &str) -> ParseError {
+        ParseError::new(message, self.pos)
+    }
+
+    fn peek(&self) -> Option<u8> {
+        self.json.as_bytes().get(self.pos).cloned()
+    }
+
+    fn skip_whitespace(&mut self) {
+        while let Some(b' ') | Some(b'\t') | Some(b'\n') | Some(b'\r') = self.peek() {
+            self.pos += 1;
+        }
+    }
+
+    fn expect(&mut self, token: &str) -> Result<(), ParseError> {
+        if self.json[self.pos..].starts_with(token) {
+            self.pos += token.len();
+            Ok(())
+        } else {
+            Err(self.error(&format!("expected `{}`", token)))
+        }
+    }
+
+    fn parse_value(&mut self) -> Result<Value, ParseError> {
+        self.skip_whitespace();
+        match self.peek() {
+            Some(b'n') => self.expect("null").map(|_| Value::Null),
+            Some(b't') => self.expect("true").map(|_| Value::Bool(true)),
+            Some(b'f') => self.expect("false").map(|_| Value::Bool(false)),
+            Some(b'"') => self.parse_string().map(Value::String),
+            Some(b'[') | Some(b'{') => {
+                if self.depth == MAX_DEPTH {
+                    return Err(self.error("too deeply nested"));
+                }
+                self.depth += 1;
+                let value = if self.peek() == Some(b'[') {
+                    self.parse_array()
+                } else {
+                    self.parse_object()
+                };
+                self.depth -= 1;
+                value
+            }
+            Some(b'-') | Some(b'0'..=b'9') => self.parse_number(),
+            Some(_) => Err(self.error("unexpected character")),
+            None => Err(self.error("unexpected end of input")),
+        }
+    }
+
+    fn parse_array(&mut self) -> Result<Value, ParseError> {
+        self.expect("[")?;
+        let mut elements = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b']') {
+            self.pos += 1;
+            return Ok(Value::Array(elements));
+        }
+        loop {
+            elements.push(self.parse_value()?);
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b']') => {
+                    self.pos += 1;
+                    return Ok(Value::Array(elements));
+                }
+                _ => return Err(self.error("expected `,` or `]`")),
+            }
+        }
+    }
+
+    fn parse_object(&mut self) -> Result<Value, ParseError> {
+        self.expect("{")?;
+        let mut members = vec![];
+        self.skip_whitespace();
+        if self.peek() == Some(b'}') {
+            self.pos += 1;
+            return Ok(Value::Object(members));
+        }
+        loop {
+            self.skip_whitespace();
+            let key = self.parse_string()?;
+            self.skip_whitespace();
+            self.expect(":")?;
+            members.push((key, self.parse_value()?));
+            self.skip_whitespace();
+            match self.peek() {
+                Some(b',') => self.pos += 1,
+                Some(b'}') => {
+                    self.pos += 1;
+                    return Ok(Value::Object(members));
+                }
+                _ => return Err(self.error("expected `,` or `}`")),
+            }
+        }
+    }
+
+    fn parse_number(&mut self) -> Result<Value, ParseError> {
+        let start = self.pos;
+        while let Some(b'-') | Some(b'+') | Some(b'.') | Some(b'e') | Some(b'E') | Some(b'0'..=b'9') =
+            self.peek()
+        {
+            self.pos += 1;
+        }
+        self.json[start..self.pos]
+            .parse()
+            .map(Value::Number)
+            .map_err(|_| ParseError::new("invalid number", start))
+    }
+
+    fn parse_hex4(&mut self) -> Result<u32, ParseError> {
+        let digits = self.json.get(self.pos..self.pos + 4)
+            .ok_or_else(|| self.error("truncated escape"))?;
+        let code = u32::from_str_radix(digits, 16).map_err(|_| self.error("invalid escape"))?;
+        self.pos += 4;
+        Ok(code)
+    }
+
+    fn parse_string(&mut self) -> Result<String, ParseError> {
+        self.expect("\"")?;
+        let mut s = String::new();
+        loop {
+            let c = self.json[self.pos..].chars().next()
+                .ok_or_else(|| self.error("unterminated string"))?;
+            self.pos += c.len_utf8();
+            match c {
+                '"' => return Ok(s),
+                '\\' => {
+                    let escape = self.peek().ok_or_else(|| self.error("unterminated string"))?;
+                    self.pos += 1;
+                    match escape {
+                        b'"' => s.push('"'),
+                        b'\\' => s.push('\\'),
+                        b'/' => s.push('/'),
+                        b'b' => s.push('\u{8}'),
+                        b'f' => s.push('\u{c}'),
+                        b'n' => s.push('\n'),
+                        b'r' => s.push('\r'),
+                        b't' => s.push('\t'),
+                        b'u' => {
+                            let mut code = self.parse_hex4()?;
+                            if (0xd800..0xdc00).contains(&code) {
+                                // A surrogate pair.
+                                self.expect("\\u")?;
+                                let low = self.parse_hex4()?;
+                                if !(0xdc00..0xe000).contains(&low) {
+                                    return Err(self.error("invalid surrogate pair"));
+                                }
+                                code = 0x10000 + ((code - 0xd800) << 10) + (low - 0xdc00);
+                            }
+                            s.push(char::from_u32(code).ok_or_else(|| self.error("invalid escape"))?);
+                        }
+                        _ => return Err(self.error("invalid escape")),
+                    }
+                }
+                c => s.push(c),
+            }
+        }
+    }
+}
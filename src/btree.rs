@@ -0,0 +1,119 @@
+//! A model of the nodes behind std's `BTreeMap` and `BTreeSet`.
+//!
+//! Every node is a separate heap block. Leaves hold up to `CAPACITY` keys and values, a pointer
+//! to their parent and their index in it; internal nodes are leaves followed by `2 * B` edges.
+//! The map doesn't tell us how many nodes it has, but the addresses of its keys do: the keys of a
+//! node are stored next to each other, an in-order walk visits all keys of a leaf in a row, and
+//! visits the keys of an internal node one at a time, in between its subtrees.
+//!
+//! A map without keys is taken to have no nodes, which under-counts a map emptied with `remove`
+//! or `pop_first`: it keeps its root leaf. There are no keys to find that leaf by, and the map's
+//! root field can't be read safely while it may be `None`.
+
+use std::alloc::Layout;
+use std::mem::{size_of, MaybeUninit};
+use std::ptr::NonNull;
+
+use SizeOps;
+
+const B: usize = 6;
+const CAPACITY: usize = 2 * B - 1;
+
+// These mirror `alloc::collections::btree::node::{LeafNode, InternalNode}`.
+#[allow(dead_code)]
+struct LeafNode<K, V> {
+    parent: Option<NonNull<InternalNode<K, V>>>,
+    parent_idx: MaybeUninit<u16>,
+    len: u16,
+    keys: [MaybeUninit<K>; CAPACITY],
+    vals: [MaybeUninit<V>; CAPACITY],
+}
+
+#[allow(dead_code)]
+#[repr(C)]
+struct InternalNode<K, V> {
+    data: LeafNode<K, V>,
+    edges: [MaybeUninit<NonNull<LeafNode<K, V>>>; 2 * B],
+}
+
+/// The number of nodes of each kind in a tree.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct BTreeShape {
+    pub leaves: usize,
+    pub internals: usize,
+}
+
+impl BTreeShape {
+    /// Work out the shape of a tree with `len` keys of type `K`, given their addresses in order.
+    pub fn new<K, I>(len: usize, keys: I) -> BTreeShape
+        where I: Iterator<Item = *const K>
+    {
+        let key_size = size_of::<K>();
+        if len == 0 {
+            // Possibly an emptied tree that still has its root leaf; see above.
+            return BTreeShape { leaves: 0, internals: 0 };
+        }
+        if len == 1 {
+            return BTreeShape { leaves: 1, internals: 0 };
+        }
+        if key_size == 0 {
+            // Zero-sized keys all share an address, so all we can do is assume full nodes.
+            return BTreeShape::estimate(len);
+        }
+
+        // Apart from the root, every node holds at least `B - 1` keys, so a run of a single key
+        // in a tree of more than one key is a key of an internal node.
+        let mut leaves = 0;
+        let mut internal_keys = vec![];
+        let mut run_start = 0;
+        let mut run_len = 0;
+        let mut previous = 0;
+        for key in keys {
+            let key = key as usize;
+            if run_len > 0 && key == previous + key_size {
+                run_len += 1;
+            } else {
+                match run_len {
+                    0 => {}
+                    1 => internal_keys.push(run_start),
+                    _ => leaves += 1,
+                }
+                run_start = key;
+                run_len = 1;
+            }
+            previous = key;
+        }
+        // The last key is always in a leaf.
+        leaves += 1;
+
+        // The keys of one internal node are adjacent in memory.
+        internal_keys.sort_unstable();
+        let internals = internal_keys.iter().zip(internal_keys.iter().skip(1))
+            .filter(|&(&a, &b)| b != a + key_size)
+            .count() + if internal_keys.is_empty() { 0 } else { 1 };
+
+        BTreeShape { leaves, internals }
+    }
+
+    /// The shape of the smallest tree that can hold `len` keys.
+    fn estimate(len: usize) -> BTreeShape {
+        let leaves = len.div_ceil(CAPACITY);
+        let mut internals = 0;
+        let mut level = leaves;
+        while level > 1 {
+            level = level.div_ceil(2 * B);
+            internals += level;
+        }
+        BTreeShape { leaves, internals }
+    }
+
+    /// The number of bytes taken up by the nodes of this tree, with keys of type `K` and values
+    /// of type `V`. We can't measure the nodes, so `ops` gives each its requested or estimated
+    /// size.
+    pub fn size_of<K, V>(&self, ops: &mut SizeOps) -> usize {
+        let leaf = Layout::new::<LeafNode<K, V>>();
+        let internal = Layout::new::<InternalNode<K, V>>();
+        (0..self.leaves).map(|_| ops.estimate_block(leaf)).sum::<usize>() +
+            (0..self.internals).map(|_| ops.estimate_block(internal)).sum::<usize>()
+    }
+}
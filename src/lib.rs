@@ -1,31 +1,121 @@
 //! Data structure measurement.
+//!
+//! With the default `std` feature disabled this is a `no_std` crate that only needs `alloc`. The
+//! trait and the implementations for `core` and `alloc` types are still available, but there is
+//! no default `UsableSizeProvider`: register one for your `#[global_allocator]` with
+//! `set_usable_size_provider` before measuring anything.
 
-#[cfg(target_os = "windows")]
+#![cfg_attr(not(feature = "std"), no_std)]
+
+#[macro_use]
+extern crate alloc;
+#[cfg(not(feature = "std"))]
+extern crate core as std;
+#[cfg(all(feature = "std", target_os = "windows"))]
 extern crate winapi;
 
-#[cfg(target_os = "windows")]
-use winapi::um::heapapi::{GetProcessHeap, HeapSize, HeapValidate};
-use std::borrow::Cow;
-use std::cell::{Cell, RefCell};
-use std::collections::{BTreeMap, HashSet, HashMap, LinkedList, VecDeque};
-use std::hash::BuildHasher;
-use std::hash::Hash;
+use alloc::borrow::{Cow, ToOwned};
+use alloc::boxed::Box;
+use alloc::collections::{BinaryHeap, BTreeMap, BTreeSet, LinkedList, VecDeque};
+use alloc::ffi::CString;
+use alloc::rc::{self, Rc};
+use alloc::string::String;
+#[cfg(target_has_atomic = "ptr")]
+use alloc::sync::{self, Arc};
+use alloc::vec::Vec;
+use std::alloc::Layout;
+use std::any::Any;
+use std::cell::{Cell, OnceCell, RefCell};
+#[cfg(feature = "lazy")]
+use std::cell::LazyCell;
+use std::cmp::{Ordering, Reverse};
+#[cfg(feature = "std")]
+use std::collections::{HashSet, HashMap};
+use std::ffi::{c_void, CStr};
+#[cfg(feature = "std")]
+use std::ffi::{OsStr, OsString};
+#[cfg(feature = "std")]
+use std::hash::{BuildHasher, Hash};
 use std::marker::PhantomData;
-use std::mem::{size_of, align_of};
-use std::net::{Ipv4Addr, Ipv6Addr};
-use std::ops::{Range, RangeFrom, RangeFull, RangeTo};
-use std::os::raw::c_void;
-use std::sync::Arc;
-use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicUsize};
-use std::rc::Rc;
+use std::mem::{self, size_of, align_of};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::num::{NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI128, NonZeroIsize};
+use std::num::{NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU128, NonZeroUsize};
+use std::num::{Saturating, Wrapping};
+use std::ops::{self, Range, RangeFrom, RangeFull, RangeTo};
+#[cfg(feature = "std")]
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, AtomicIsize, AtomicPtr, AtomicUsize};
+#[cfg(target_has_atomic = "8")]
+use std::sync::atomic::{AtomicI8, AtomicU8};
+#[cfg(target_has_atomic = "16")]
+use std::sync::atomic::{AtomicI16, AtomicU16};
+#[cfg(target_has_atomic = "32")]
+use std::sync::atomic::{AtomicI32, AtomicU32};
+#[cfg(target_has_atomic = "64")]
+use std::sync::atomic::{AtomicI64, AtomicU64};
+#[cfg(feature = "std")]
+use std::sync::{Condvar, Mutex, OnceLock, RwLock, TryLockError};
+#[cfg(all(feature = "std", feature = "lazy"))]
+use std::sync::LazyLock;
+#[cfg(feature = "std")]
+use std::sync::mpsc::{Receiver, Sender, SyncSender};
+use std::time::Duration;
+#[cfg(feature = "std")]
+use std::time::{Instant, SystemTime};
+
+#[cfg(feature = "std")]
+pub mod about_memory;
+mod btree;
+mod provider;
+mod report;
+mod size_class;
+#[cfg(feature = "std")]
+mod table;
+#[cfg(feature = "std")]
+pub mod testing;
+mod validate;
+
+pub use provider::{SetProviderError, UsableSizeProvider};
+pub use provider::{set_usable_size_provider, usable_size_provider};
+pub use report::{MemoryReport, Reporter};
+pub use size_class::SizeClassModel;
+#[cfg(feature = "std")]
+pub use provider::SystemProvider;
+#[cfg(feature = "glibc")]
+pub use provider::GlibcProvider;
+#[cfg(feature = "jemalloc")]
+pub use provider::JemallocProvider;
+#[cfg(feature = "mimalloc")]
+pub use provider::MimallocProvider;
+use btree::BTreeShape;
+#[cfg(feature = "std")]
+use table::TableLayout;
+pub use validate::HeapSizeError;
+use validate::validate;
+
+// For `#[derive(HeapSizeOf)]`, whose expansion can't name `Layout` through `std` or `core` in
+// every crate.
+#[doc(hidden)]
+pub use std::alloc::Layout as __Layout;
+
+// The set of addresses behind `SizeOps::with_seen_set`.
+#[cfg(feature = "std")]
+type SeenSet = HashSet<usize>;
+#[cfg(not(feature = "std"))]
+type SeenSet = BTreeSet<usize>;
 
 /// Get the size of a heap block.
 ///
 /// Ideally Rust would expose a function like this in std::rt::heap.
 ///
-/// `unsafe` because the caller must ensure that the pointer is from jemalloc.
-/// FIXME: This probably interacts badly with custom allocators:
-/// https://doc.rust-lang.org/book/custom-allocators.html
+/// The size is obtained from the registered `UsableSizeProvider`; see
+/// `set_usable_size_provider`.
+///
+/// # Safety
+///
+/// The caller must ensure that the pointer is null, dangling (as used for zero-sized
+/// allocations), or the start of a live block from the allocator the provider describes.
 pub unsafe fn heap_size_of<T>(ptr: *const T) -> usize {
     if ptr as usize <= align_of::<T>() {
         0
@@ -34,39 +124,467 @@ pub unsafe fn heap_size_of<T>(ptr: *const T) -> usize {
     }
 }
 
-#[cfg(not(target_os = "windows"))]
 unsafe fn heap_size_of_impl(ptr: *const c_void) -> usize {
-    // The C prototype is `je_malloc_usable_size(JEMALLOC_USABLE_SIZE_CONST void *ptr)`. On some
-    // platforms `JEMALLOC_USABLE_SIZE_CONST` is `const` and on some it is empty. But in practice
-    // this function doesn't modify the contents of the block that `ptr` points to, so we use
-    // `*const c_void` here.
-    extern "C" {
-		#[cfg_attr(any(prefixed_jemalloc, target_os = "macos", target_os = "ios", target_os = "android"), link_name = "je_malloc_usable_size")]
-        fn malloc_usable_size(ptr: *const c_void) -> usize;
+    usable_size_provider().usable_size(ptr)
+}
+
+/// Get the size of a heap block, or an error if the pointer can't be the start of one.
+///
+/// Unlike `heap_size_of`, this checks the pointer before asking the allocator about it: with the
+/// allocator itself when the registered `UsableSizeProvider` can tell, and otherwise (on Linux
+/// and Android) by ruling out the current thread's stack and the static memory of loaded images.
+/// Pointers into the stacks of other threads, or to blocks of a different allocator, are only
+/// caught if the allocator can tell.
+///
+/// # Safety
+///
+/// If the checks pass, the pointer is measured like with `heap_size_of`, so it must not point
+/// into the middle of a heap block or into other memory the checks don't cover.
+pub unsafe fn try_heap_size_of<T>(ptr: *const T) -> Result<usize, HeapSizeError> {
+    if ptr as usize <= align_of::<T>() {
+        Ok(0)
+    } else {
+        validate(ptr as *const c_void)?;
+        Ok(heap_size_of_impl(ptr as *const c_void))
+    }
+}
+
+/// The state carried through a single measurement.
+///
+/// This holds the function used to measure heap blocks and, optionally, the set of pointers
+/// already visited during the traversal. It is passed down through
+/// `HeapSizeOf::heap_size_of_children_with`.
+///
+/// Heap blocks are either measured by asking the allocator, or estimated from the `Layout` they
+/// were requested with (see `SizeOps::estimate`).
+pub struct SizeOps {
+    /// Measures the heap block starting at the given pointer.
+    size_of_op: unsafe fn(*const c_void) -> usize,
+
+    /// Turns the layout of a heap block into its size, if we are estimating instead of asking
+    /// the allocator.
+    estimate_op: Option<fn(Layout) -> usize>,
+
+    /// The addresses of shared allocations already counted, if we are deduplicating them.
+    seen: Option<SeenSet>,
+
+    /// How reference-counted blocks are counted.
+    shared_policy: SharedPolicy,
+
+    /// The pointers that failed validation, if we are validating them.
+    offenders: Option<Vec<(usize, HeapSizeError)>>,
+
+    /// The bytes of the blocks counted so far beyond what was requested for them.
+    slop: usize,
+
+    /// The bytes of the blocks counted so far reserved for elements that aren't there yet.
+    spare: usize,
+
+    /// The number of locks and cells we couldn't look into.
+    contended: usize,
+
+    /// The number of values whose heap memory we have no way to find.
+    unmeasured: usize,
+}
+
+impl SizeOps {
+    /// Measure heap blocks with the registered `UsableSizeProvider`, without remembering which
+    /// pointers have been visited.
+    pub fn new() -> SizeOps {
+        SizeOps::with_size_of_op(heap_size_of_impl)
+    }
+
+    /// Estimate the size of heap blocks as the number of bytes requested for them, without
+    /// asking the allocator.
+    ///
+    /// This gives the same results everywhere, whatever the allocator, but doesn't count the
+    /// bytes allocators add when rounding requests up. Only implementations that know the layout
+    /// of the blocks they own, through `heap_size_of_block`, can be estimated.
+    pub fn estimate() -> SizeOps {
+        fn requested_size(layout: Layout) -> usize {
+            layout.size()
+        }
+        SizeOps::estimate_with(requested_size)
+    }
+
+    /// Estimate the size of heap blocks from their layout with a custom function, e.g. one that
+    /// rounds requests up to the size classes of a particular allocator.
+    ///
+    /// The function is only called for blocks of a non-zero size.
+    pub fn estimate_with(estimate_op: fn(Layout) -> usize) -> SizeOps {
+        SizeOps {
+            estimate_op: Some(estimate_op),
+            ..SizeOps::with_size_of_op(heap_size_of_impl)
+        }
+    }
+
+    /// Estimate the size of heap blocks as what `model`'s allocator would hand out for them.
+    pub fn estimate_with_model(model: SizeClassModel) -> SizeOps {
+        fn jemalloc(layout: Layout) -> usize {
+            SizeClassModel::Jemalloc.usable_size(layout)
+        }
+        fn glibc(layout: Layout) -> usize {
+            SizeClassModel::Glibc.usable_size(layout)
+        }
+        fn mimalloc(layout: Layout) -> usize {
+            SizeClassModel::Mimalloc.usable_size(layout)
+        }
+        fn windows(layout: Layout) -> usize {
+            SizeClassModel::Windows.usable_size(layout)
+        }
+        SizeOps::estimate_with(match model {
+            SizeClassModel::Jemalloc => jemalloc,
+            SizeClassModel::Glibc => glibc,
+            SizeClassModel::Mimalloc => mimalloc,
+            SizeClassModel::Windows => windows,
+        })
+    }
+
+    /// Measure heap blocks with a custom function.
+    pub fn with_size_of_op(size_of_op: unsafe fn(*const c_void) -> usize) -> SizeOps {
+        SizeOps {
+            size_of_op,
+            estimate_op: None,
+            seen: None,
+            shared_policy: SharedPolicy::Skip,
+            offenders: None,
+            slop: 0,
+            spare: 0,
+            contended: 0,
+            unmeasured: 0,
+        }
+    }
+
+    /// Also remember the pointers visited during the traversal; see `have_seen_ptr`. This also
+    /// counts reference-counted blocks once, as with `SharedPolicy::Once`.
+    pub fn with_seen_set(mut self) -> SizeOps {
+        self.seen = Some(SeenSet::new());
+        self.shared_policy = SharedPolicy::Once;
+        self
+    }
+
+    /// Count reference-counted blocks according to `policy`. The default is
+    /// `SharedPolicy::Skip`.
+    pub fn with_shared_policy(mut self, policy: SharedPolicy) -> SizeOps {
+        self.set_shared_policy(policy);
+        self
+    }
+
+    /// Count reference-counted blocks according to `policy` from now on, and return the policy
+    /// used so far. This lets part of a traversal, like a single field, count them differently.
+    pub fn set_shared_policy(&mut self, policy: SharedPolicy) -> SharedPolicy {
+        if policy == SharedPolicy::Once && self.seen.is_none() {
+            self.seen = Some(SeenSet::new());
+        }
+        mem::replace(&mut self.shared_policy, policy)
+    }
+
+    /// Validate every pointer before measuring it, as `try_heap_size_of` does. Pointers that
+    /// fail are counted as 0 bytes and recorded; see `offenders`.
+    ///
+    /// This is meant for debugging `HeapSizeOf` implementations, and makes measurement slower.
+    pub fn with_checks(mut self) -> SizeOps {
+        self.offenders = Some(vec![]);
+        self
+    }
+
+    /// Whether this measurement estimates the size of heap blocks instead of asking the
+    /// allocator.
+    pub fn estimates(&self) -> bool {
+        self.estimate_op.is_some()
+    }
+
+    /// How this measurement counts reference-counted blocks.
+    pub fn shared_policy(&self) -> SharedPolicy {
+        self.shared_policy
+    }
+
+    /// Whether this measurement remembers the pointers it visits.
+    pub fn has_seen_set(&self) -> bool {
+        self.seen.is_some()
+    }
+
+    /// The pointers that failed validation so far, and why. Always empty without
+    /// `with_checks`.
+    pub fn offenders(&self) -> &[(usize, HeapSizeError)] {
+        match self.offenders {
+            Some(ref offenders) => offenders,
+            None => &[],
+        }
+    }
+
+    /// The bytes of the blocks counted so far that weren't requested from the allocator, i.e.
+    /// what it added when rounding requests up. Only blocks counted with a known layout (through
+    /// `heap_size_of_block` or `estimate_block`) can have any; see `measure`.
+    pub fn slop(&self) -> usize {
+        self.slop
+    }
+
+    /// The bytes of the blocks counted so far that collections reserved for elements they don't
+    /// hold yet; see `heap_size_of_buffer`.
+    pub fn spare(&self) -> usize {
+        self.spare
+    }
+
+    /// Record that `bytes` of the blocks counted are spare capacity.
+    ///
+    /// Collections that can't use `heap_size_of_buffer` call this after counting their blocks.
+    pub fn add_spare(&mut self, bytes: usize) {
+        self.spare += bytes;
+    }
+
+    /// The number of `Mutex`es, `RwLock`s and `RefCell`s whose contents weren't measured because
+    /// they were locked or borrowed mutably at the time. Measuring never waits for a lock.
+    pub fn contended(&self) -> usize {
+        self.contended
+    }
+
+    /// Record that the contents of a lock couldn't be measured; see `contended`.
+    pub fn add_contended(&mut self) {
+        self.contended += 1;
+    }
+
+    /// The number of values whose heap memory was left out because there is no way to measure
+    /// it, such as the messages queued in `mpsc` channels. If this isn't 0, the sizes counted
+    /// so far are missing some memory.
+    pub fn unmeasured(&self) -> usize {
+        self.unmeasured
+    }
+
+    /// Record that the heap memory of a value couldn't be measured at all; see `unmeasured`.
+    pub fn add_unmeasured(&mut self) {
+        self.unmeasured += 1;
+    }
+
+    /// Measure the heap-allocated structures that hang off `value`, split into the bytes that
+    /// were requested for them and the allocator's slop, and the requested bytes into those in
+    /// use and the spare capacity.
+    pub fn measure<T: HeapSizeOf + ?Sized>(&mut self, value: &T) -> HeapSize {
+        let (slop_before, spare_before) = (self.slop, self.spare);
+        let total = value.heap_size_of_children_with(self);
+        let slop = (self.slop - slop_before).min(total);
+        let spare = (self.spare - spare_before).min(total - slop);
+        HeapSize { requested: total - slop, slop, spare }
+    }
+
+    /// Get the size of a heap block, like `heap_size_of` but with this measurement's function.
+    ///
+    /// The whole block counts as requested; use `heap_size_of_block` where the layout it was
+    /// allocated with is known. When estimating, the allocator isn't asked and the block counts
+    /// as `estimate_block(Layout::new::<T>())`, which is only right if `ptr` points to a `T` that
+    /// was allocated on its own.
+    ///
+    /// # Safety
+    ///
+    /// The same as for `heap_size_of`, for whatever allocator the size function describes.
+    pub unsafe fn heap_size_of<T>(&mut self, ptr: *const T) -> usize {
+        if ptr as usize <= align_of::<T>() {
+            return 0;
+        }
+        if self.estimates() {
+            return self.estimate_block(Layout::new::<T>());
+        }
+        if let Some(ref mut offenders) = self.offenders {
+            if let Err(error) = validate(ptr as *const c_void) {
+                offenders.push((ptr as usize, error));
+                return 0;
+            }
+        }
+        (self.size_of_op)(ptr as *const c_void)
+    }
+
+    /// Get the size of the heap block starting at `ptr`, which was allocated with `layout`.
+    ///
+    /// When estimating this is `estimate_block(layout)` and `ptr` isn't used beyond checking that
+    /// it isn't null; otherwise the block is measured like with `heap_size_of`. Blocks of a zero
+    /// size are never allocated, and like null pointers count as 0 bytes.
+    ///
+    /// # Safety
+    ///
+    /// Unless `layout` has a zero size, the same as for `heap_size_of`.
+    pub unsafe fn heap_size_of_block<T: ?Sized>(&mut self, ptr: *const T, layout: Layout) -> usize {
+        if layout.size() == 0 || ptr.is_null() {
+            0
+        } else if self.estimates() {
+            self.estimate_block(layout)
+        } else {
+            let size = self.heap_size_of(ptr as *const c_void);
+            self.add_slop(layout, size)
+        }
+    }
+
+    /// Get the size of the buffer starting at `ptr` of a collection that holds `len` elements
+    /// out of a `capacity`, as allocated by `Vec`; the rest of the buffer is spare capacity.
+    ///
+    /// # Safety
+    ///
+    /// The same as for `heap_size_of_block`, with the layout of an array of `capacity` elements.
+    pub unsafe fn heap_size_of_buffer<T>(&mut self, ptr: *const T, capacity: usize, len: usize)
+                                         -> usize {
+        let size = self.heap_size_of_block(ptr, array_layout::<T>(capacity));
+        if size > 0 {
+            self.add_spare((capacity - len) * size_of::<T>());
+        }
+        size
+    }
+
+    /// Count a heap block allocated with `layout` without looking at it: at its estimated size
+    /// when estimating, and otherwise at its requested size.
+    ///
+    /// Implementations use this for blocks they can't find or measure.
+    pub fn estimate_block(&mut self, layout: Layout) -> usize {
+        match self.estimate_op {
+            Some(_) if layout.size() == 0 => 0,
+            Some(estimate_op) => {
+                let size = estimate_op(layout);
+                self.add_slop(layout, size)
+            }
+            None => layout.size(),
+        }
+    }
+
+    fn add_slop(&mut self, layout: Layout, size: usize) -> usize {
+        self.slop += size.saturating_sub(layout.size());
+        size
+    }
+
+    /// Record `ptr` as visited and return whether it had been visited before. Always returns
+    /// false if there is no seen set.
+    pub fn have_seen_ptr<T: ?Sized>(&mut self, ptr: *const T) -> bool {
+        match self.seen {
+            Some(ref mut seen) => !seen.insert(ptr as *const () as usize),
+            None => false,
+        }
+    }
+}
+
+impl Default for SizeOps {
+    fn default() -> SizeOps {
+        SizeOps::new()
+    }
+}
+
+/// How a measurement counts the blocks behind `Rc`, `Arc` and their `Weak` pointers, which may
+/// be reached through any number of pointers.
+///
+/// Whenever a block is counted, that includes the reference counts at its start as well as the
+/// value. `Weak` pointers never count what hangs off the value, which they don't keep alive; they
+/// only count the block once the value has been dropped and the block lives on because of them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SharedPolicy {
+    /// Count what hangs off the value for every pointer, but never the block itself, which we
+    /// assume something else owns.
+    Skip,
+    /// Count the block and what hangs off the value for every pointer, as if each owned them.
+    Always,
+    /// Count the block and what hangs off the value for the first pointer to reach it, and
+    /// nothing for the others. This uses the seen set, and gives correct totals for graphs that
+    /// share nodes.
+    Once,
+}
+
+/// A measurement split into the bytes requested from the allocator and the slop it added.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct HeapSize {
+    /// The bytes that were asked for: the capacity of a `Vec` or `String`, the size of the value
+    /// in a `Box`, and so on.
+    pub requested: usize,
+    /// The bytes the allocator added when rounding the requests up.
+    pub slop: usize,
+    /// The part of `requested` that collections reserved for elements they don't hold yet.
+    pub spare: usize,
+}
+
+impl HeapSize {
+    /// The usable size of the blocks, as `heap_size_of_children` would measure it.
+    pub fn total(&self) -> usize {
+        self.requested + self.slop
+    }
+
+    /// The part of `requested` that holds something.
+    pub fn used(&self) -> usize {
+        self.requested - self.spare
     }
-    malloc_usable_size(ptr)
 }
 
-#[cfg(target_os = "windows")]
-unsafe fn heap_size_of_impl(mut ptr: *const c_void) -> usize {
-    let heap = GetProcessHeap();
+impl ops::Add for HeapSize {
+    type Output = HeapSize;
 
-    if HeapValidate(heap, 0, ptr) == 0 {
-        ptr = *(ptr as *const *const c_void).offset(-1);
+    fn add(self, other: HeapSize) -> HeapSize {
+        HeapSize {
+            requested: self.requested + other.requested,
+            slop: self.slop + other.slop,
+            spare: self.spare + other.spare,
+        }
     }
+}
 
-    HeapSize(heap, 0, ptr) as usize
+impl ops::AddAssign for HeapSize {
+    fn add_assign(&mut self, other: HeapSize) {
+        *self = *self + other;
+    }
 }
 
 // The simplest trait for measuring the size of heap data structures. More complex traits that
 // return multiple measurements -- e.g. measure text separately from images -- are also possible,
 // and should be used when appropriate.
 //
+/// Measurement of the heap data hanging off a value.
+///
+/// The trait is object safe, so values of different types can be measured through
+/// `Box<dyn HeapSizeOf>`, `Rc<dyn HeapSizeOf>` and so on, and it can be made a supertrait of your
+/// own traits to measure collections like `Vec<Box<dyn Component>>`:
+///
+/// ```
+/// use heapsize::{HeapSizeOf, SizeOps};
+///
+/// trait Component: HeapSizeOf {}
+///
+/// struct Label(String);
+///
+/// impl HeapSizeOf for Label {
+///     fn heap_size_of_children(&self) -> usize {
+///         self.heap_size_of_children_with(&mut SizeOps::new())
+///     }
+///
+///     fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+///         self.0.heap_size_of_children_with(ops)
+///     }
+/// }
+///
+/// impl Component for Label {}
+///
+/// let components: Vec<Box<dyn Component>> = vec![Box::new(Label("OK".to_owned()))];
+/// assert_eq!(components.heap_size_of_children_with(&mut SizeOps::estimate()),
+///            components.capacity() * 16 + 24 + 2);
+/// ```
+///
+/// Boxes of trait objects count the block holding the value at the size its vtable gives.
+/// `dyn Any` can't say what hangs off the value, so for it only that block is counted.
 pub trait HeapSizeOf {
     /// Measure the size of any heap-allocated structures that hang off this value, but not the
     /// space taken up by the value itself (i.e. what size_of::<T> measures, more or less); that
     /// space is handled by the implementation of HeapSizeOf for Box<T> below.
     fn heap_size_of_children(&self) -> usize;
+
+    /// Like `heap_size_of_children`, but as part of the measurement described by `ops`.
+    ///
+    /// Implementations that own other values should override this and pass `ops` on to them.
+    /// The default ignores `ops`, so implementations that only provide
+    /// `heap_size_of_children` keep working.
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        let _ = ops;
+        self.heap_size_of_children()
+    }
+
+    /// Report the size of the heap-allocated structures that hang off this value at `path`.
+    ///
+    /// The default reports everything at `path` itself. Implementations for values with distinct
+    /// parts, like derived ones for structs, report each part at its own path below `path`.
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = self.heap_size_of_children_with(reporter.ops());
+        reporter.report(path, size);
+    }
 }
 
 // There are two possible ways to measure the size of `self` when it's on the heap: compute it
@@ -86,28 +604,117 @@ pub trait HeapSizeOf {
 //
 impl<T: HeapSizeOf + ?Sized> HeapSizeOf for Box<T> {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         // Measure size of `self`.
         unsafe {
-            heap_size_of(&**self as *const T as *const c_void) + (**self).heap_size_of_children()
+            ops.heap_size_of_block(&**self as *const T, Layout::for_value(&**self)) +
+                (**self).heap_size_of_children_with(ops)
         }
     }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = unsafe {
+            reporter.ops().heap_size_of_block(&**self as *const T, Layout::for_value(&**self))
+        };
+        reporter.report(path, size);
+        (**self).report_heap_size(path, reporter);
+    }
 }
 
 impl<T: HeapSizeOf> HeapSizeOf for [T] {
     fn heap_size_of_children(&self) -> usize {
-        self.iter().fold(0, |size, item| size + item.heap_size_of_children())
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.iter().fold(0, |size, item| size + item.heap_size_of_children_with(ops))
+    }
+
+    // The items are reported at the slice's own path, so that the parts of structs add up by name
+    // across items.
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        reporter.report(path, 0);
+        for item in self {
+            item.report_heap_size(path, reporter);
+        }
+    }
+}
+
+impl<T: HeapSizeOf, const N: usize> HeapSizeOf for [T; N] {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self[..].heap_size_of_children_with(ops)
+    }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        self[..].report_heap_size(path, reporter)
     }
 }
 
 impl HeapSizeOf for String {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         unsafe {
-            heap_size_of(self.as_ptr())
+            ops.heap_size_of_buffer(self.as_ptr(), self.capacity(), self.len())
+        }
+    }
+}
+
+// Strings from the OS live in a byte buffer like `String`'s, but only give access to their bytes.
+#[cfg(feature = "std")]
+impl HeapSizeOf for OsString {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        let bytes = self.as_encoded_bytes();
+        if bytes.is_empty() {
+            estimate_buffer::<u8>(ops, self.capacity(), 0)
+        } else {
+            unsafe { ops.heap_size_of_buffer(bytes.as_ptr(), self.capacity(), bytes.len()) }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl HeapSizeOf for PathBuf {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        let bytes = self.as_os_str().as_encoded_bytes();
+        if bytes.is_empty() {
+            estimate_buffer::<u8>(ops, self.capacity(), 0)
+        } else {
+            unsafe { ops.heap_size_of_buffer(bytes.as_ptr(), self.capacity(), bytes.len()) }
         }
     }
 }
 
-impl<'a, T: ?Sized> HeapSizeOf for &'a T {
+// A `CString` is a boxed slice of its bytes and the nul terminator.
+impl HeapSizeOf for CString {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        let bytes = self.as_bytes_with_nul();
+        unsafe { ops.heap_size_of_block(bytes.as_ptr(), Layout::for_value(bytes)) }
+    }
+}
+
+impl<T: ?Sized> HeapSizeOf for &T {
     fn heap_size_of_children(&self) -> usize {
         0
     }
@@ -134,27 +741,46 @@ impl<T: ?Sized> HeapSizeOf for *const T {
 
 impl<T: HeapSizeOf> HeapSizeOf for Option<T> {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         match *self {
             None => 0,
-            Some(ref x) => x.heap_size_of_children()
+            Some(ref x) => x.heap_size_of_children_with(ops)
+        }
+    }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        match *self {
+            None => reporter.report(path, 0),
+            Some(ref x) => x.report_heap_size(path, reporter),
         }
     }
 }
 
 impl<T: HeapSizeOf, E: HeapSizeOf> HeapSizeOf for Result<T, E> {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         match *self {
-            Ok(ref x) => x.heap_size_of_children(),
-            Err(ref e) => e.heap_size_of_children(),
+            Ok(ref x) => x.heap_size_of_children_with(ops),
+            Err(ref e) => e.heap_size_of_children_with(ops),
         }
     }
 }
 
 impl<'a, B: ?Sized + ToOwned> HeapSizeOf for Cow<'a, B> where B::Owned: HeapSizeOf {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         match *self {
             Cow::Borrowed(_) => 0,
-            Cow::Owned(ref b) => b.heap_size_of_children(),
+            Cow::Owned(ref b) => b.heap_size_of_children_with(ops),
         }
     }
 }
@@ -165,113 +791,414 @@ impl HeapSizeOf for () {
     }
 }
 
-impl<T1, T2> HeapSizeOf for (T1, T2)
-    where T1: HeapSizeOf, T2 :HeapSizeOf
-{
-    fn heap_size_of_children(&self) -> usize {
-        self.0.heap_size_of_children() +
-            self.1.heap_size_of_children()
+macro_rules! impl_tuple {
+    ($($name:ident),+) => (
+        impl<$($name: HeapSizeOf),+> HeapSizeOf for ($($name,)+) {
+            fn heap_size_of_children(&self) -> usize {
+                self.heap_size_of_children_with(&mut SizeOps::new())
+            }
+
+            #[allow(non_snake_case)]
+            fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+                let ($(ref $name,)+) = *self;
+                0 $(+ $name.heap_size_of_children_with(ops))+
+            }
+        }
+    );
+}
+
+impl_tuple!(T1);
+impl_tuple!(T1, T2);
+impl_tuple!(T1, T2, T3);
+impl_tuple!(T1, T2, T3, T4);
+impl_tuple!(T1, T2, T3, T4, T5);
+impl_tuple!(T1, T2, T3, T4, T5, T6);
+impl_tuple!(T1, T2, T3, T4, T5, T6, T7);
+impl_tuple!(T1, T2, T3, T4, T5, T6, T7, T8);
+impl_tuple!(T1, T2, T3, T4, T5, T6, T7, T8, T9);
+impl_tuple!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10);
+impl_tuple!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11);
+impl_tuple!(T1, T2, T3, T4, T5, T6, T7, T8, T9, T10, T11, T12);
+
+// `Rc<T>` and `Arc<T>` point at the value inside a `#[repr(C)]` heap block that starts with the
+// strong and weak reference counts. Find the start of that block, and the layout it was allocated
+// with, from the value and its layout.
+fn refcounted_block<T: ?Sized>(value: *const T, layout: Layout) -> (*const c_void, Layout) {
+    let (block, offset) = Layout::new::<[usize; 2]>().extend(layout).unwrap();
+    ((value as *const u8).wrapping_sub(offset) as *const c_void, block.pad_to_align())
+}
+
+// The layout of the buffer of a `Vec<T>` or similar with the given capacity. It can't overflow,
+// since the buffer exists.
+fn array_layout<T>(capacity: usize) -> Layout {
+    Layout::array::<T>(capacity).unwrap()
+}
+
+// Count the buffer of a collection like `SizeOps::heap_size_of_buffer` does, for when we can't
+// find it.
+fn estimate_buffer<T>(ops: &mut SizeOps, capacity: usize, len: usize) -> usize {
+    let size = ops.estimate_block(array_layout::<T>(capacity));
+    if size > 0 {
+        ops.add_spare((capacity - len) * size_of::<T>());
     }
+    size
 }
 
-impl<T1, T2, T3> HeapSizeOf for (T1, T2, T3)
-    where T1: HeapSizeOf, T2 :HeapSizeOf, T3: HeapSizeOf
-{
+// Shared pointers are measured according to the measurement's `SharedPolicy`.
+fn measure_refcounted<T: HeapSizeOf + ?Sized>(value: &T, ops: &mut SizeOps) -> usize {
+    match ops.shared_policy() {
+        SharedPolicy::Skip => value.heap_size_of_children_with(ops),
+        SharedPolicy::Once if ops.have_seen_ptr(value as *const T) => 0,
+        SharedPolicy::Once | SharedPolicy::Always => unsafe {
+            let (block, layout) = refcounted_block(value as *const T, Layout::for_value(value));
+            ops.heap_size_of_block(block, layout) + value.heap_size_of_children_with(ops)
+        },
+    }
+}
+
+// A weak pointer is all that keeps the block alive once the value has been dropped. `value` is
+// dangling (`usize::MAX`) for pointers made with `Weak::new`, which have no block.
+fn measure_weak<T>(value: *const T, strong_count: usize, ops: &mut SizeOps) -> usize {
+    if strong_count > 0 || value as usize == usize::MAX {
+        return 0;
+    }
+    match ops.shared_policy() {
+        SharedPolicy::Skip => 0,
+        SharedPolicy::Once if ops.have_seen_ptr(value) => 0,
+        SharedPolicy::Once | SharedPolicy::Always => unsafe {
+            let (block, layout) = refcounted_block(value, Layout::new::<T>());
+            ops.heap_size_of_block(block, layout)
+        },
+    }
+}
+
+impl<T: HeapSizeOf + ?Sized> HeapSizeOf for Rc<T> {
     fn heap_size_of_children(&self) -> usize {
-        self.0.heap_size_of_children() +
-            self.1.heap_size_of_children() +
-            self.2.heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        measure_refcounted(&**self, ops)
     }
 }
 
-impl<T1, T2, T3, T4> HeapSizeOf for (T1, T2, T3, T4)
-    where T1: HeapSizeOf, T2 :HeapSizeOf, T3: HeapSizeOf, T4: HeapSizeOf
-{
+#[cfg(target_has_atomic = "ptr")]
+impl<T: HeapSizeOf + ?Sized> HeapSizeOf for Arc<T> {
     fn heap_size_of_children(&self) -> usize {
-        self.0.heap_size_of_children() +
-            self.1.heap_size_of_children() +
-            self.2.heap_size_of_children() +
-            self.3.heap_size_of_children()
-  }
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        measure_refcounted(&**self, ops)
+    }
 }
 
-impl<T1, T2, T3, T4, T5> HeapSizeOf for (T1, T2, T3, T4, T5)
-    where T1: HeapSizeOf, T2 :HeapSizeOf, T3: HeapSizeOf, T4: HeapSizeOf, T5: HeapSizeOf
-{
+impl<T> HeapSizeOf for rc::Weak<T> {
     fn heap_size_of_children(&self) -> usize {
-        self.0.heap_size_of_children() +
-            self.1.heap_size_of_children() +
-            self.2.heap_size_of_children() +
-            self.3.heap_size_of_children() +
-            self.4.heap_size_of_children()
-  }
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        measure_weak(self.as_ptr(), self.strong_count(), ops)
+    }
 }
 
-impl<T: HeapSizeOf> HeapSizeOf for Arc<T> {
+#[cfg(target_has_atomic = "ptr")]
+impl<T> HeapSizeOf for sync::Weak<T> {
     fn heap_size_of_children(&self) -> usize {
-        (**self).heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        measure_weak(self.as_ptr(), self.strong_count(), ops)
     }
 }
 
 impl<T: HeapSizeOf> HeapSizeOf for RefCell<T> {
     fn heap_size_of_children(&self) -> usize {
-        self.borrow().heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        match self.try_borrow() {
+            Ok(value) => value.heap_size_of_children_with(ops),
+            Err(_) => {
+                ops.add_contended();
+                0
+            }
+        }
+    }
+}
+
+impl<T: HeapSizeOf> HeapSizeOf for OnceCell<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.get().map_or(0, |value| value.heap_size_of_children_with(ops))
+    }
+}
+
+// Measuring a lazy value doesn't force it. That needs `LazyCell::get`, which is only stable since
+// Rust 1.94, hence the `lazy` feature.
+#[cfg(feature = "lazy")]
+#[clippy::msrv = "1.94"]
+impl<T: HeapSizeOf, F: FnOnce() -> T> HeapSizeOf for LazyCell<T, F> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        LazyCell::get(self).map_or(0, |value| value.heap_size_of_children_with(ops))
+    }
+}
+
+// Locks are measured through `try_lock`, so that measuring never blocks or deadlocks (std's
+// locks aren't reentrant). A lock that is held elsewhere isn't measured, and is counted in
+// `SizeOps::contended`. A poisoned lock is measured all the same.
+#[cfg(feature = "std")]
+impl<T: HeapSizeOf + ?Sized> HeapSizeOf for Mutex<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        match self.try_lock() {
+            Ok(value) => value.heap_size_of_children_with(ops),
+            Err(TryLockError::Poisoned(error)) => error.into_inner().heap_size_of_children_with(ops),
+            Err(TryLockError::WouldBlock) => {
+                ops.add_contended();
+                0
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: HeapSizeOf + ?Sized> HeapSizeOf for RwLock<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        match self.try_read() {
+            Ok(value) => value.heap_size_of_children_with(ops),
+            Err(TryLockError::Poisoned(error)) => error.into_inner().heap_size_of_children_with(ops),
+            Err(TryLockError::WouldBlock) => {
+                ops.add_contended();
+                0
+            }
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+impl<T: HeapSizeOf> HeapSizeOf for OnceLock<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.get().map_or(0, |value| value.heap_size_of_children_with(ops))
+    }
+}
+
+#[cfg(all(feature = "std", feature = "lazy"))]
+#[clippy::msrv = "1.94"]
+impl<T: HeapSizeOf, F: FnOnce() -> T> HeapSizeOf for LazyLock<T, F> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        LazyLock::get(self).map_or(0, |value| value.heap_size_of_children_with(ops))
     }
 }
 
 impl<T: HeapSizeOf + Copy> HeapSizeOf for Cell<T> {
     fn heap_size_of_children(&self) -> usize {
-        self.get().heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.get().heap_size_of_children_with(ops)
     }
 }
 
 impl<T: HeapSizeOf> HeapSizeOf for Vec<T> {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         self.iter().fold(
-            unsafe { heap_size_of(self.as_ptr()) },
-            |n, elem| n + elem.heap_size_of_children())
+            unsafe { ops.heap_size_of_buffer(self.as_ptr(), self.capacity(), self.len()) },
+            |n, elem| n + elem.heap_size_of_children_with(ops))
+    }
+
+    // Collections report their own blocks at their path, then their elements at the same path
+    // like slices do.
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = unsafe {
+            reporter.ops().heap_size_of_buffer(self.as_ptr(), self.capacity(), self.len())
+        };
+        reporter.report(path, size);
+        self[..].report_heap_size(path, reporter);
+    }
+}
+
+// Find the start of the buffer of a `VecDeque`. If the contents wrap around, the second slice
+// starts there. Otherwise the first slice starts `head` elements in, and we look for the buffer
+// pointer among the words of the deque, insisting on finding `head` as well. That is only done
+// while the deque is made of `DEQUE_WORDS` words (buffer pointer, capacity, head and length), so
+// that there is no padding to read.
+const DEQUE_WORDS: usize = 4;
+
+fn vec_deque_buffer<T>(deque: &VecDeque<T>) -> Option<*const T> {
+    let (front, back) = deque.as_slices();
+    if !back.is_empty() {
+        return Some(back.as_ptr());
+    }
+    if front.is_empty() || size_of::<T>() == 0 ||
+       size_of::<VecDeque<T>>() != DEQUE_WORDS * size_of::<usize>() {
+        return None;
+    }
+
+    let start = front.as_ptr() as usize;
+    let words = deque as *const VecDeque<T> as *const usize;
+    let words = (0..DEQUE_WORDS).map(|i| unsafe { words.add(i).read() });
+    words.clone()
+        .filter(|&buffer| buffer <= start && (start - buffer).is_multiple_of(size_of::<T>()))
+        .find(|&buffer| {
+            let head = (start - buffer) / size_of::<T>();
+            head < deque.capacity() && words.clone().any(|word| word == head)
+        })
+        .map(|buffer| buffer as *const T)
+}
+
+fn vec_deque_size<T>(deque: &VecDeque<T>, ops: &mut SizeOps) -> usize {
+    match vec_deque_buffer(deque) {
+        Some(buffer) => unsafe { ops.heap_size_of_buffer(buffer, deque.capacity(), deque.len()) },
+        // An empty deque may still have a buffer, and an unfamiliar layout hides it; either way
+        // the requested size is a lower bound.
+        None => estimate_buffer::<T>(ops, deque.capacity(), deque.len()),
     }
 }
 
 impl<T: HeapSizeOf> HeapSizeOf for VecDeque<T> {
     fn heap_size_of_children(&self) -> usize {
-        self.iter().fold(
-            // FIXME: get the buffer pointer for heap_size_of(), capacity() is a lower bound:
-            self.capacity() * size_of::<T>(),
-            |n, elem| n + elem.heap_size_of_children())
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        let size = vec_deque_size(self, ops);
+        self.iter().fold(size, |n, elem| n + elem.heap_size_of_children_with(ops))
+    }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = vec_deque_size(self, reporter.ops());
+        reporter.report(path, size);
+        for elem in self {
+            elem.report_heap_size(path, reporter);
+        }
     }
 }
 
-impl<T> HeapSizeOf for Vec<Rc<T>> {
+impl<T: HeapSizeOf> HeapSizeOf for BinaryHeap<T> {
     fn heap_size_of_children(&self) -> usize {
-        // The fate of measuring Rc<T> is still undecided, but we still want to measure
-        // the space used for storing them.
-        unsafe {
-            heap_size_of(self.as_ptr())
-        }
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.as_slice().iter().fold(
+            unsafe { ops.heap_size_of_buffer(self.as_slice().as_ptr(), self.capacity(), self.len()) },
+            |n, elem| n + elem.heap_size_of_children_with(ops))
+    }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = unsafe {
+            reporter.ops().heap_size_of_buffer(self.as_slice().as_ptr(), self.capacity(), self.len())
+        };
+        reporter.report(path, size);
+        self.as_slice().report_heap_size(path, reporter);
+    }
+}
+
+#[cfg(feature = "std")]
+fn hash_set_table_size<T, S: BuildHasher>(set: &HashSet<T, S>, ops: &mut SizeOps) -> usize {
+    // The buckets of a set hold just the values.
+    let buckets = set.iter().map(|value| value as *const T);
+    let size = unsafe {
+        TableLayout::new::<T>(set.capacity()).size_of(set, set.hasher(), buckets, ops)
+    };
+    if size > 0 {
+        ops.add_spare((set.capacity() - set.len()) * size_of::<T>());
     }
+    size
 }
 
+#[cfg(feature = "std")]
 impl<T: HeapSizeOf, S> HeapSizeOf for HashSet<T, S>
     where T: Eq + Hash, S: BuildHasher {
     fn heap_size_of_children(&self) -> usize {
-        //TODO(#6908) measure actual bucket memory usage instead of approximating
-        let size = self.capacity() * (size_of::<T>() + size_of::<usize>());
-        self.iter().fold(size, |n, value| {
-            n + value.heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.iter().fold(hash_set_table_size(self, ops), |n, value| {
+            n + value.heap_size_of_children_with(ops)
         })
     }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = hash_set_table_size(self, reporter.ops());
+        reporter.report(path, size);
+        for value in self {
+            value.report_heap_size(path, reporter);
+        }
+    }
+}
+
+#[cfg(feature = "std")]
+fn hash_map_table_size<K, V, S: BuildHasher>(map: &HashMap<K, V, S>, ops: &mut SizeOps) -> usize {
+    // The buckets of a map hold `(K, V)` tuples.
+    let key_offset = std::mem::offset_of!((K, V), 0);
+    let buckets = map.keys()
+        .map(|key| (key as *const K as *const u8).wrapping_sub(key_offset) as *const (K, V));
+    let size = unsafe {
+        TableLayout::new::<(K, V)>(map.capacity()).size_of(map, map.hasher(), buckets, ops)
+    };
+    // The spare capacity is the room for more buckets, not counting the buckets the table keeps
+    // empty to stay fast.
+    if size > 0 {
+        ops.add_spare((map.capacity() - map.len()) * size_of::<(K, V)>());
+    }
+    size
 }
 
+#[cfg(feature = "std")]
 impl<K: HeapSizeOf, V: HeapSizeOf, S> HeapSizeOf for HashMap<K, V, S>
     where K: Eq + Hash, S: BuildHasher {
     fn heap_size_of_children(&self) -> usize {
-        //TODO(#6908) measure actual bucket memory usage instead of approximating
-        let size = self.capacity() * (size_of::<V>() + size_of::<K>() + size_of::<usize>());
-        self.iter().fold(size, |n, (key, value)| {
-            n + key.heap_size_of_children() + value.heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.iter().fold(hash_map_table_size(self, ops), |n, (key, value)| {
+            n + key.heap_size_of_children_with(ops) + value.heap_size_of_children_with(ops)
         })
     }
+
+    // Keys and values are reported at the map's own path, like the elements of a `Vec`.
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let size = hash_map_table_size(self, reporter.ops());
+        reporter.report(path, size);
+        for (key, value) in self {
+            key.report_heap_size(path, reporter);
+            value.report_heap_size(path, reporter);
+        }
+    }
 }
 
 // PhantomData is always 0.
@@ -281,27 +1208,134 @@ impl<T> HeapSizeOf for PhantomData<T> {
     }
 }
 
-// A linked list has an overhead of two words per item.
+// Like raw pointers, we don't know what an atomic pointer points to.
+impl<T> HeapSizeOf for AtomicPtr<T> {
+    fn heap_size_of_children(&self) -> usize {
+        0
+    }
+}
+
+/// Counts as nothing: std gives no way to look at the messages queued in a channel without
+/// receiving them. Neither the queued messages nor the channel's own blocks are included in any
+/// measurement. Each end of a channel is recorded in `SizeOps::unmeasured` instead, so that the
+/// missing memory shows.
+#[cfg(feature = "std")]
+impl<T> HeapSizeOf for Sender<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        ops.add_unmeasured();
+        0
+    }
+}
+
+/// Counts as nothing, like `Sender`.
+#[cfg(feature = "std")]
+impl<T> HeapSizeOf for SyncSender<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        ops.add_unmeasured();
+        0
+    }
+}
+
+/// Counts as nothing, like `Sender`.
+#[cfg(feature = "std")]
+impl<T> HeapSizeOf for Receiver<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        ops.add_unmeasured();
+        0
+    }
+}
+
+// A linked list has an overhead of two words per item. We can't find the nodes from the items,
+// since we don't know where in a node its item is.
+#[allow(dead_code)]
+struct ListNode<T> {
+    next: *const ListNode<T>,
+    prev: *const ListNode<T>,
+    element: T,
+}
+
 impl<T: HeapSizeOf> HeapSizeOf for LinkedList<T> {
     fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
         let mut size = 0;
         for item in self {
-            size += 2 * size_of::<usize>() + size_of::<T>() + item.heap_size_of_children();
+            size += ops.estimate_block(Layout::new::<ListNode<T>>()) +
+                item.heap_size_of_children_with(ops);
         }
         size
     }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        reporter.report(path, 0);
+        for item in self {
+            let size = reporter.ops().estimate_block(Layout::new::<ListNode<T>>());
+            reporter.report(path, size);
+            item.report_heap_size(path, reporter);
+        }
+    }
 }
 
-// FIXME: Overhead for the BTreeMap nodes is not accounted for.
+/// The nodes are estimated from their layout, since they can't be found. A map that had entries
+/// and was emptied again keeps an empty root node, which isn't counted.
 impl<K: HeapSizeOf, V: HeapSizeOf> HeapSizeOf for BTreeMap<K, V> {
     fn heap_size_of_children(&self) -> usize {
-        let mut size = 0;
-        for (key, value) in self.iter() {
-            size += size_of::<(K, V)>() +
-                    key.heap_size_of_children() +
-                    value.heap_size_of_children();
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        let shape = BTreeShape::new(self.len(), self.keys().map(|key| key as *const K));
+        self.iter().fold(shape.size_of::<K, V>(ops), |n, (key, value)| {
+            n + key.heap_size_of_children_with(ops) + value.heap_size_of_children_with(ops)
+        })
+    }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let shape = BTreeShape::new(self.len(), self.keys().map(|key| key as *const K));
+        let size = shape.size_of::<K, V>(reporter.ops());
+        reporter.report(path, size);
+        for (key, value) in self {
+            key.report_heap_size(path, reporter);
+            value.report_heap_size(path, reporter);
+        }
+    }
+}
+
+/// Estimated like `BTreeMap`, with the same under-count for emptied sets.
+impl<T: HeapSizeOf> HeapSizeOf for BTreeSet<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        // A set is a map with zero-sized values.
+        let shape = BTreeShape::new(self.len(), self.iter().map(|value| value as *const T));
+        self.iter().fold(shape.size_of::<T, ()>(ops), |n, value| {
+            n + value.heap_size_of_children_with(ops)
+        })
+    }
+
+    fn report_heap_size(&self, path: &str, reporter: &mut Reporter) {
+        let shape = BTreeShape::new(self.len(), self.iter().map(|value| value as *const T));
+        let size = shape.size_of::<T, ()>(reporter.ops());
+        reporter.report(path, size);
+        for value in self {
+            value.report_heap_size(path, reporter);
         }
-        size
     }
 }
 
@@ -310,7 +1344,11 @@ where
     T: HeapSizeOf,
 {
     fn heap_size_of_children(&self) -> usize {
-        self.start.heap_size_of_children() + self.end.heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.start.heap_size_of_children_with(ops) + self.end.heap_size_of_children_with(ops)
     }
 }
 
@@ -319,7 +1357,11 @@ where
     T: HeapSizeOf,
 {
     fn heap_size_of_children(&self) -> usize {
-        self.start.heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.start.heap_size_of_children_with(ops)
     }
 }
 
@@ -328,7 +1370,41 @@ where
     T: HeapSizeOf,
 {
     fn heap_size_of_children(&self) -> usize {
-        self.end.heap_size_of_children()
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.end.heap_size_of_children_with(ops)
+    }
+}
+
+impl<T: HeapSizeOf> HeapSizeOf for Wrapping<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.0.heap_size_of_children_with(ops)
+    }
+}
+
+impl<T: HeapSizeOf> HeapSizeOf for Saturating<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.0.heap_size_of_children_with(ops)
+    }
+}
+
+impl<T: HeapSizeOf> HeapSizeOf for Reverse<T> {
+    fn heap_size_of_children(&self) -> usize {
+        self.heap_size_of_children_with(&mut SizeOps::new())
+    }
+
+    fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+        self.0.heap_size_of_children_with(ops)
     }
 }
 
@@ -359,8 +1435,28 @@ macro_rules! known_heap_size(
 );
 
 known_heap_size!(0, char, str);
-known_heap_size!(0, u8, u16, u32, u64, usize);
-known_heap_size!(0, i8, i16, i32, i64, isize);
+known_heap_size!(0, u8, u16, u32, u64, u128, usize);
+known_heap_size!(0, i8, i16, i32, i64, i128, isize);
 known_heap_size!(0, bool, f32, f64);
+known_heap_size!(0, NonZeroU8, NonZeroU16, NonZeroU32, NonZeroU64, NonZeroU128, NonZeroUsize);
+known_heap_size!(0, NonZeroI8, NonZeroI16, NonZeroI32, NonZeroI64, NonZeroI128, NonZeroIsize);
+known_heap_size!(0, Ordering, std::sync::atomic::Ordering, Duration);
+known_heap_size!(0, CStr);
+#[cfg(feature = "std")]
+known_heap_size!(0, OsStr, Path, Instant, SystemTime);
 known_heap_size!(0, AtomicBool, AtomicIsize, AtomicUsize);
-known_heap_size!(0, Ipv4Addr, Ipv6Addr, RangeFull);
+#[cfg(target_has_atomic = "8")]
+known_heap_size!(0, AtomicI8, AtomicU8);
+#[cfg(target_has_atomic = "16")]
+known_heap_size!(0, AtomicI16, AtomicU16);
+#[cfg(target_has_atomic = "32")]
+known_heap_size!(0, AtomicI32, AtomicU32);
+#[cfg(target_has_atomic = "64")]
+known_heap_size!(0, AtomicI64, AtomicU64);
+#[cfg(feature = "std")]
+known_heap_size!(0, Condvar);
+known_heap_size!(0, IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr, SocketAddrV4, SocketAddrV6);
+known_heap_size!(0, RangeFull);
+
+// All we know about a `dyn Any` is its size, which is enough to measure the box it's in.
+known_heap_size!(0, dyn Any, dyn Any + Send, dyn Any + Send + Sync);
@@ -0,0 +1,284 @@
+//! Pluggable sources of usable-size information for heap blocks.
+//!
+//! `heap_size_of` asks a process-wide `UsableSizeProvider` how big a heap block is. By default
+//! that is the provider for the allocator selected through cargo features (falling back to the
+//! platform's system allocator), but a binary that installs a different `#[global_allocator]`
+//! can register a matching provider with `set_usable_size_provider` before measuring anything.
+//!
+//! Without the `std` feature there is no system allocator to fall back to, so unless one of the
+//! allocator features is enabled a provider has to be registered before measuring anything.
+
+use std::error::Error;
+use std::fmt;
+use std::ffi::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+#[cfg(all(feature = "std", target_os = "windows"))]
+use winapi::um::heapapi::{GetProcessHeap, HeapSize, HeapValidate};
+#[cfg(all(feature = "std", target_os = "windows"))]
+use winapi::um::memoryapi::VirtualQuery;
+#[cfg(all(feature = "std", target_os = "windows"))]
+use winapi::um::winnt::{MEMORY_BASIC_INFORMATION, MEM_COMMIT, PAGE_GUARD};
+#[cfg(all(feature = "std", target_os = "windows"))]
+use winapi::um::winnt::{PAGE_EXECUTE_READ, PAGE_EXECUTE_READWRITE, PAGE_EXECUTE_WRITECOPY};
+#[cfg(all(feature = "std", target_os = "windows"))]
+use winapi::um::winnt::{PAGE_READONLY, PAGE_READWRITE, PAGE_WRITECOPY};
+
+/// Something that can report the usable size of the heap blocks handed out by an allocator.
+///
+/// Implement this for the allocator your binary actually uses and register it with
+/// `set_usable_size_provider`.
+pub trait UsableSizeProvider: Sync {
+    /// Get the usable size of the heap block starting at `ptr`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` must point to the start of a live heap block obtained from this provider's
+    /// allocator.
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize;
+
+    /// Whether `ptr` is the start of a live block from this provider's allocator, or `None` if
+    /// the allocator can't tell. Used by `try_heap_size_of`.
+    ///
+    /// # Safety
+    ///
+    /// `ptr` may be any non-null pointer, but implementations may read the memory just before it.
+    unsafe fn owns(&self, ptr: *const c_void) -> Option<bool> {
+        let _ = ptr;
+        None
+    }
+}
+
+/// The platform's system allocator: `malloc_usable_size` on Linux and most other Unixes,
+/// `malloc_size` on macOS and iOS, and `HeapSize` on Windows.
+#[cfg(feature = "std")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SystemProvider;
+
+#[cfg(all(feature = "std", not(any(target_os = "windows", target_os = "macos", target_os = "ios"))))]
+impl UsableSizeProvider for SystemProvider {
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize {
+        // The C prototype is `malloc_usable_size(void *ptr)`, but in practice this function
+        // doesn't modify the contents of the block that `ptr` points to, so we use
+        // `*const c_void` here.
+        extern "C" {
+            fn malloc_usable_size(ptr: *const c_void) -> usize;
+        }
+        malloc_usable_size(ptr)
+    }
+}
+
+#[cfg(all(feature = "std", any(target_os = "macos", target_os = "ios")))]
+impl UsableSizeProvider for SystemProvider {
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize {
+        extern "C" {
+            fn malloc_size(ptr: *const c_void) -> usize;
+        }
+        malloc_size(ptr)
+    }
+}
+
+#[cfg(all(feature = "std", target_os = "windows"))]
+impl UsableSizeProvider for SystemProvider {
+    unsafe fn usable_size(&self, mut ptr: *const c_void) -> usize {
+        let heap = GetProcessHeap();
+
+        if HeapValidate(heap, 0, ptr) == 0 {
+            ptr = *(ptr as *const *const c_void).offset(-1);
+        }
+
+        HeapSize(heap, 0, ptr) as usize
+    }
+
+    unsafe fn owns(&self, ptr: *const c_void) -> Option<bool> {
+        let heap = GetProcessHeap();
+        if HeapValidate(heap, 0, ptr) != 0 {
+            return Some(true);
+        }
+
+        // Over-aligned blocks store the pointer to the start of the real block just before the
+        // pointer that was handed out. Anything else may have nothing readable there, so check
+        // before following it.
+        if !preceding_word_is_readable(ptr) {
+            return Some(false);
+        }
+        // The word may also hold some unrelated heap pointer, so the block it points to has to
+        // contain `ptr`, as the real block of an over-aligned one does.
+        let block = *(ptr as *const *const c_void).offset(-1);
+        if block >= ptr || HeapValidate(heap, 0, block) == 0 {
+            return Some(false);
+        }
+        Some((ptr as usize - block as usize) < HeapSize(heap, 0, block) as usize)
+    }
+}
+
+// Whether the word just before `ptr` is in committed memory that can be read.
+#[cfg(all(feature = "std", target_os = "windows"))]
+unsafe fn preceding_word_is_readable(ptr: *const c_void) -> bool {
+    use std::mem::{self, align_of, size_of};
+
+    // An aligned word can't straddle two pages, so one query covers it.
+    if !(ptr as usize).is_multiple_of(align_of::<usize>()) || (ptr as usize) < size_of::<usize>() {
+        return false;
+    }
+    let word = (ptr as *const usize).offset(-1);
+    let mut info: MEMORY_BASIC_INFORMATION = mem::zeroed();
+    if VirtualQuery(word as *const _, &mut info, size_of::<MEMORY_BASIC_INFORMATION>()) == 0 {
+        return false;
+    }
+    let readable = PAGE_READONLY | PAGE_READWRITE | PAGE_WRITECOPY |
+        PAGE_EXECUTE_READ | PAGE_EXECUTE_READWRITE | PAGE_EXECUTE_WRITECOPY;
+    info.State == MEM_COMMIT && info.Protect & readable != 0 && info.Protect & PAGE_GUARD == 0
+}
+
+/// glibc's ptmalloc, through `malloc_usable_size`.
+#[cfg(feature = "glibc")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct GlibcProvider;
+
+#[cfg(feature = "glibc")]
+impl UsableSizeProvider for GlibcProvider {
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize {
+        extern "C" {
+            fn malloc_usable_size(ptr: *const c_void) -> usize;
+        }
+        malloc_usable_size(ptr)
+    }
+}
+
+/// jemalloc as built by `jemalloc-sys`/`tikv-jemalloc-sys`, whose symbols carry the `_rjem_`
+/// prefix unless they were built with unprefixed malloc (in which case `GlibcProvider` or
+/// `SystemProvider` already reach jemalloc).
+#[cfg(feature = "jemalloc")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct JemallocProvider;
+
+#[cfg(feature = "jemalloc")]
+impl UsableSizeProvider for JemallocProvider {
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize {
+        // On some platforms the pointer is declared `const` and on some it is not; in practice
+        // this function never modifies the block, so `*const c_void` is fine.
+        extern "C" {
+            #[link_name = "_rjem_malloc_usable_size"]
+            fn malloc_usable_size(ptr: *const c_void) -> usize;
+        }
+        malloc_usable_size(ptr)
+    }
+}
+
+/// mimalloc, through `mi_usable_size`.
+#[cfg(feature = "mimalloc")]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct MimallocProvider;
+
+#[cfg(feature = "mimalloc")]
+impl UsableSizeProvider for MimallocProvider {
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize {
+        extern "C" {
+            fn mi_usable_size(ptr: *const c_void) -> usize;
+        }
+        mi_usable_size(ptr)
+    }
+
+    unsafe fn owns(&self, ptr: *const c_void) -> Option<bool> {
+        extern "C" {
+            fn mi_is_in_heap_region(ptr: *const c_void) -> bool;
+        }
+        Some(mi_is_in_heap_region(ptr))
+    }
+}
+
+#[cfg(feature = "mimalloc")]
+static DEFAULT_PROVIDER: MimallocProvider = MimallocProvider;
+#[cfg(all(feature = "jemalloc", not(feature = "mimalloc")))]
+static DEFAULT_PROVIDER: JemallocProvider = JemallocProvider;
+#[cfg(all(feature = "glibc", not(any(feature = "jemalloc", feature = "mimalloc"))))]
+static DEFAULT_PROVIDER: GlibcProvider = GlibcProvider;
+#[cfg(all(feature = "std", not(feature = "no-system-provider"),
+          not(any(feature = "glibc", feature = "jemalloc", feature = "mimalloc"))))]
+static DEFAULT_PROVIDER: SystemProvider = SystemProvider;
+#[cfg(not(any(all(feature = "std", not(feature = "no-system-provider")),
+              feature = "glibc", feature = "jemalloc", feature = "mimalloc")))]
+static DEFAULT_PROVIDER: MissingProvider = MissingProvider;
+
+// The default when there is nothing we know how to ask, or when we are not supposed to link the
+// system allocator's usable size function.
+#[cfg(not(any(all(feature = "std", not(feature = "no-system-provider")),
+              feature = "glibc", feature = "jemalloc", feature = "mimalloc")))]
+struct MissingProvider;
+
+#[cfg(not(any(all(feature = "std", not(feature = "no-system-provider")),
+              feature = "glibc", feature = "jemalloc", feature = "mimalloc")))]
+impl UsableSizeProvider for MissingProvider {
+    unsafe fn usable_size(&self, _: *const c_void) -> usize {
+        panic!("no usable size provider has been registered; \
+                call heapsize::set_usable_size_provider with one for the global allocator")
+    }
+}
+
+// The same dance as `log::set_logger`: the provider may only be set once, and readers only look
+// at `PROVIDER` after observing `INITIALIZED`.
+const UNINITIALIZED: usize = 0;
+const INITIALIZING: usize = 1;
+const INITIALIZED: usize = 2;
+
+static STATE: AtomicUsize = AtomicUsize::new(UNINITIALIZED);
+static mut PROVIDER: &dyn UsableSizeProvider = &DEFAULT_PROVIDER;
+
+/// Register the provider used by `heap_size_of` for the rest of the process.
+///
+/// This can only be done once, and should be done before any measurement happens: blocks
+/// measured before the call are measured with the default provider, which is chosen by the
+/// `mimalloc`, `jemalloc` and `glibc` features (in that order of preference) or is
+/// `SystemProvider` if none of them is enabled. Without the `std` feature, or with the
+/// `no-system-provider` feature, `SystemProvider` isn't the default, and asking the allocator
+/// about anything before registering a provider panics.
+///
+/// On targets without atomic compare-and-swap (e.g. `thumbv6m-none-eabi`) the registration only
+/// uses loads and stores, so it must not race with another call to this function, e.g. from an
+/// interrupt handler.
+pub fn set_usable_size_provider(
+    provider: &'static dyn UsableSizeProvider,
+) -> Result<(), SetProviderError> {
+    #[cfg(target_has_atomic = "ptr")]
+    let claimed = STATE
+        .compare_exchange(UNINITIALIZED, INITIALIZING, Ordering::Acquire, Ordering::Relaxed)
+        .is_ok();
+    #[cfg(not(target_has_atomic = "ptr"))]
+    let claimed = if STATE.load(Ordering::Acquire) == UNINITIALIZED {
+        STATE.store(INITIALIZING, Ordering::Relaxed);
+        true
+    } else {
+        false
+    };
+
+    if !claimed {
+        return Err(SetProviderError(()));
+    }
+    unsafe {
+        PROVIDER = provider;
+    }
+    STATE.store(INITIALIZED, Ordering::Release);
+    Ok(())
+}
+
+/// The provider currently used by `heap_size_of`.
+pub fn usable_size_provider() -> &'static dyn UsableSizeProvider {
+    if STATE.load(Ordering::Acquire) == INITIALIZED {
+        unsafe { PROVIDER }
+    } else {
+        &DEFAULT_PROVIDER
+    }
+}
+
+/// The error returned by `set_usable_size_provider` if a provider has already been registered.
+#[derive(Debug)]
+pub struct SetProviderError(());
+
+impl fmt::Display for SetProviderError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("a usable size provider has already been registered")
+    }
+}
+
+impl Error for SetProviderError {}
@@ -0,0 +1,204 @@
+//! Hierarchical measurements.
+//!
+//! `HeapSizeOf::report_heap_size` breaks a measurement down into a tree of named nodes, addressed
+//! by `/`-separated paths such as `cache/entries/value`. Sizes are reported at the leaves and roll
+//! up into their ancestors.
+
+use alloc::borrow::ToOwned;
+use alloc::string::String;
+use alloc::vec::Vec;
+
+use SizeOps;
+
+/// A node of a hierarchical measurement.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct MemoryReport {
+    name: String,
+    own_size: usize,
+    own_slop: usize,
+    own_spare: usize,
+    own_unmeasured: usize,
+    own_contended: usize,
+    children: Vec<MemoryReport>,
+}
+
+impl MemoryReport {
+    fn new(name: &str) -> MemoryReport {
+        MemoryReport {
+            name: name.to_owned(),
+            own_size: 0,
+            own_slop: 0,
+            own_spare: 0,
+            own_unmeasured: 0,
+            own_contended: 0,
+            children: vec![],
+        }
+    }
+
+    /// The last segment of this node's path; empty for the root.
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    /// The bytes reported at this node itself, not counting its children.
+    pub fn own_size(&self) -> usize {
+        self.own_size
+    }
+
+    /// The bytes reported at this node and everything below it.
+    pub fn size(&self) -> usize {
+        self.children.iter().fold(self.own_size, |n, child| n + child.size())
+    }
+
+    /// The part of `own_size` that the allocator added when rounding requests up.
+    pub fn own_slop(&self) -> usize {
+        self.own_slop
+    }
+
+    /// The part of `size` that the allocator added when rounding requests up.
+    pub fn slop(&self) -> usize {
+        self.children.iter().fold(self.own_slop, |n, child| n + child.slop())
+    }
+
+    /// The part of `own_size` that collections reserved for elements they don't hold yet.
+    pub fn own_spare(&self) -> usize {
+        self.own_spare
+    }
+
+    /// The part of `size` that collections reserved for elements they don't hold yet.
+    pub fn spare(&self) -> usize {
+        self.children.iter().fold(self.own_spare, |n, child| n + child.spare())
+    }
+
+    /// The number of values reported at this node itself whose heap memory couldn't be
+    /// measured; see `SizeOps::unmeasured`.
+    pub fn own_unmeasured(&self) -> usize {
+        self.own_unmeasured
+    }
+
+    /// The number of values reported at this node and everything below it whose heap memory
+    /// couldn't be measured. If this isn't 0, `size` is missing some memory.
+    pub fn unmeasured(&self) -> usize {
+        self.children.iter().fold(self.own_unmeasured, |n, child| n + child.unmeasured())
+    }
+
+    /// The number of locks and cells reported at this node itself whose contents weren't
+    /// measured because they were held; see `SizeOps::contended`.
+    pub fn own_contended(&self) -> usize {
+        self.own_contended
+    }
+
+    /// The number of locks and cells reported at this node and everything below it whose
+    /// contents weren't measured because they were held.
+    pub fn contended(&self) -> usize {
+        self.children.iter().fold(self.own_contended, |n, child| n + child.contended())
+    }
+
+    /// The nodes directly below this one, in the order they were first reported.
+    pub fn children(&self) -> &[MemoryReport] {
+        &self.children
+    }
+
+    /// Find the node at `path`, relative to this one.
+    pub fn get(&self, path: &str) -> Option<&MemoryReport> {
+        segments(path).try_fold(self, |node, segment| {
+            node.children.iter().find(|child| child.name == segment)
+        })
+    }
+
+    fn get_or_insert(&mut self, path: &str) -> &mut MemoryReport {
+        segments(path).fold(self, |node, segment| {
+            let index = match node.children.iter().position(|child| child.name == segment) {
+                Some(index) => index,
+                None => {
+                    node.children.push(MemoryReport::new(segment));
+                    node.children.len() - 1
+                }
+            };
+            &mut node.children[index]
+        })
+    }
+}
+
+fn segments(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/').filter(|segment| !segment.is_empty())
+}
+
+/// Collects the sizes reported during a hierarchical measurement.
+pub struct Reporter {
+    ops: SizeOps,
+    root: MemoryReport,
+    // The slop, spare capacity, unmeasured values and contended locks of `ops` when we last
+    // reported.
+    reported_slop: usize,
+    reported_spare: usize,
+    reported_unmeasured: usize,
+    reported_contended: usize,
+}
+
+impl Reporter {
+    /// Start a report, measuring with `SizeOps::new()`.
+    pub fn new() -> Reporter {
+        Reporter::with_ops(SizeOps::new())
+    }
+
+    /// Start a report, measuring with the given `SizeOps`.
+    pub fn with_ops(ops: SizeOps) -> Reporter {
+        let (reported_slop, reported_spare) = (ops.slop(), ops.spare());
+        let (reported_unmeasured, reported_contended) = (ops.unmeasured(), ops.contended());
+        Reporter {
+            ops,
+            root: MemoryReport::new(""),
+            reported_slop,
+            reported_spare,
+            reported_unmeasured,
+            reported_contended,
+        }
+    }
+
+    /// The measurement that sizes are taken with.
+    pub fn ops(&mut self) -> &mut SizeOps {
+        &mut self.ops
+    }
+
+    /// Add `size` bytes at `path`, creating the nodes along it as needed.
+    ///
+    /// The slop, spare capacity, unmeasured values and contended locks `ops` found since the last
+    /// report are taken to be part of these bytes, so measure just before reporting.
+    pub fn report(&mut self, path: &str, size: usize) {
+        let slop = (self.ops.slop() - self.reported_slop).min(size);
+        let spare = (self.ops.spare() - self.reported_spare).min(size - slop);
+        self.reported_slop = self.ops.slop();
+        self.reported_spare = self.ops.spare();
+        let unmeasured = self.ops.unmeasured() - self.reported_unmeasured;
+        self.reported_unmeasured = self.ops.unmeasured();
+        let contended = self.ops.contended() - self.reported_contended;
+        self.reported_contended = self.ops.contended();
+        let node = self.root.get_or_insert(path);
+        node.own_size += size;
+        node.own_slop += slop;
+        node.own_spare += spare;
+        node.own_unmeasured += unmeasured;
+        node.own_contended += contended;
+    }
+
+    /// The path of the child `segment` of `path`.
+    pub fn join(path: &str, segment: &str) -> String {
+        if path.is_empty() {
+            segment.to_owned()
+        } else {
+            format!("{}/{}", path, segment)
+        }
+    }
+
+    /// Finish the report and return its root.
+    pub fn finish(self) -> MemoryReport {
+        self.root
+    }
+}
+
+impl Default for Reporter {
+    fn default() -> Reporter {
+        Reporter::new()
+    }
+}
@@ -0,0 +1,124 @@
+//! Models of how allocators round requests up to the sizes of the blocks they hand out.
+//!
+//! These let an estimate (see `SizeOps::estimate_with_model`) come out the same as measuring
+//! with the allocator would, and tell how many bytes of a block were never asked for.
+
+use std::alloc::Layout;
+use std::mem::size_of;
+
+/// The rounding behaviour of a particular allocator, for the target's pointer width.
+///
+/// The models cover the default configurations of the allocators, and blocks of the alignments
+/// Rust asks for in practice; heavily over-aligned blocks may be a little bigger.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SizeClassModel {
+    /// jemalloc 5 with 4KiB pages: four size classes per doubling above 128 bytes.
+    Jemalloc,
+    /// glibc's ptmalloc: chunks with a one-word header. Blocks between 128KiB and 32MiB are
+    /// assumed to come from the heap, as they do once the first mapped block has been freed;
+    /// before that they are mapped, and a page bigger. Blocks aligned to more than two words may
+    /// also be bigger, by up to their alignment.
+    Glibc,
+    /// mimalloc 2: whole words up to 8 words, four size classes per doubling above that, and
+    /// OS-sized blocks above 128KiB.
+    Mimalloc,
+    /// The Windows process heap as used by Rust's `System` allocator: exact sizes, but
+    /// over-aligned blocks are padded by their alignment.
+    Windows,
+}
+
+impl SizeClassModel {
+    /// The usable size of the block the allocator hands out for `layout`. Blocks of a zero
+    /// size are never allocated, and have no usable size.
+    pub fn usable_size(&self, layout: Layout) -> usize {
+        let (size, align) = (layout.size(), layout.align());
+        if size == 0 {
+            return 0;
+        }
+        match *self {
+            SizeClassModel::Jemalloc => jemalloc_usable_size(size, align),
+            SizeClassModel::Glibc => glibc_usable_size(size),
+            SizeClassModel::Mimalloc => mimalloc_usable_size(round_up(size, align)),
+            SizeClassModel::Windows => {
+                if align <= 2 * size_of::<usize>() && align <= size {
+                    size
+                } else {
+                    size.saturating_add(align)
+                }
+            }
+        }
+    }
+
+    /// The bytes of the block handed out for `layout` that weren't asked for.
+    pub fn slop(&self, layout: Layout) -> usize {
+        self.usable_size(layout) - layout.size()
+    }
+}
+
+fn round_up(size: usize, multiple: usize) -> usize {
+    size.div_ceil(multiple).saturating_mul(multiple)
+}
+
+// Round `size` up to one of four classes per doubling: multiples of a quarter of the power of two
+// below it.
+fn quarter_classes(size: usize) -> usize {
+    let lg = usize::BITS - 1 - (size - 1).leading_zeros();
+    round_up(size, 1 << (lg - 2))
+}
+
+fn jemalloc_usable_size(size: usize, align: usize) -> usize {
+    const PAGE: usize = 4096;
+    const LARGE_MIN: usize = 4 * PAGE;
+
+    // Small blocks are naturally aligned to their size class; large ones to pages.
+    let size = if align <= PAGE { round_up(size, align) } else { size.max(LARGE_MIN) };
+    if size <= 8 {
+        8
+    } else if size <= 128 {
+        round_up(size, 16)
+    } else {
+        quarter_classes(size)
+    }
+}
+
+fn glibc_usable_size(size: usize) -> usize {
+    const PAGE: usize = 4096;
+    let word = size_of::<usize>();
+    // The highest the threshold for mapping blocks goes, which is where it ends up in any
+    // process that has freed a big block.
+    let mmap_threshold = 4 * 1024 * 1024 * word;
+
+    // A chunk is the request plus a size word, in multiples of two words and at least four.
+    let chunk = round_up(size.saturating_add(word), 2 * word).max(4 * word);
+    if chunk < mmap_threshold {
+        // The size word of the next chunk is ours while we're in use.
+        chunk - word
+    } else {
+        // Mapped chunks keep both header words to themselves.
+        round_up(chunk + word, PAGE) - 2 * word
+    }
+}
+
+fn mimalloc_usable_size(size: usize) -> usize {
+    const MEDIUM_MAX: usize = 128 * 1024;
+    let word = size_of::<usize>();
+
+    if size <= word {
+        word
+    } else if size <= 8 * word {
+        // A whole number of words.
+        round_up(size, word)
+    } else if size <= MEDIUM_MAX {
+        quarter_classes(size.div_ceil(word)) * word
+    } else {
+        // Blocks of their own, rounded to a size the OS is happy with.
+        let granularity = match size {
+            s if s < 512 * 1024 => 4 * 1024,
+            s if s < 2 * 1024 * 1024 => 64 * 1024,
+            s if s < 8 * 1024 * 1024 => 256 * 1024,
+            s if s < 32 * 1024 * 1024 => 1024 * 1024,
+            _ => 4 * 1024 * 1024,
+        };
+        round_up(size, granularity)
+    }
+}
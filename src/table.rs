@@ -0,0 +1,148 @@
+//! Measurement of the SwissTable behind `HashMap` and `HashSet`.
+//!
+//! std's hash map (hashbrown) keeps its buckets and control bytes in a single heap block:
+//!
+//! ```text
+//! [ padding | bucket n-1 | ... | bucket 1 | bucket 0 | ctrl 0 | ... | ctrl n-1 | ctrl group ]
+//!                                                    ^ the table's `ctrl` pointer
+//! ```
+//!
+//! The map only holds the `ctrl` pointer, so we find it among the words of the table and check it
+//! against the addresses of the buckets we can see. If that doesn't work out we fall back to
+//! computing the size of the block from the capacity, using hashbrown's allocation formula.
+//!
+//! Besides the table the map holds its hasher, which may have padding that we mustn't read. We
+//! only look at the map's words when the hasher is at one end and the table's words make up the
+//! rest of the map.
+
+use std::alloc::Layout;
+use std::mem::{align_of, size_of};
+use std::os::raw::c_void;
+
+use SizeOps;
+
+// The table's fields: the bucket mask, the `ctrl` pointer, the growth left and the item count.
+const TABLE_WORDS: usize = 4;
+
+// The width of a group of control bytes, which depends on the SIMD implementation hashbrown uses
+// on this target. On targets not listed here we can compute a size with the generic width, but
+// we don't trust it enough to go looking for the heap block.
+#[cfg(all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"))]
+const GROUP_WIDTH: Option<usize> = Some(16);
+#[cfg(all(target_arch = "aarch64", target_feature = "neon", target_endian = "little"))]
+const GROUP_WIDTH: Option<usize> = Some(8);
+#[cfg(not(any(
+    all(any(target_arch = "x86", target_arch = "x86_64"), target_feature = "sse2"),
+    all(target_arch = "aarch64", target_feature = "neon", target_endian = "little"),
+)))]
+const GROUP_WIDTH: Option<usize> = None;
+
+/// The shape of the heap block of a table with buckets of type `T`.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct TableLayout {
+    /// The number of buckets; always zero or a power of two.
+    pub buckets: usize,
+    /// The offset of the control bytes from the start of the block.
+    pub ctrl_offset: usize,
+    /// The size of the block requested from the allocator.
+    pub size: usize,
+    /// The alignment of the block requested from the allocator.
+    pub align: usize,
+}
+
+impl TableLayout {
+    /// The layout of a table with buckets of type `T` that reports the given `capacity()`.
+    pub fn new<T>(capacity: usize) -> TableLayout {
+        let group_width = GROUP_WIDTH.unwrap_or(size_of::<usize>());
+        let align = if align_of::<T>() > group_width { align_of::<T>() } else { group_width };
+
+        // Tables of up to 8 buckets can be filled completely but for one bucket, larger ones up
+        // to 7/8ths.
+        let buckets = if capacity == 0 {
+            0
+        } else if capacity < 8 {
+            (capacity + 1).next_power_of_two()
+        } else {
+            (capacity / 7 * 8).next_power_of_two()
+        };
+        if buckets == 0 {
+            // An empty table points at a shared static group and has no heap block.
+            return TableLayout { buckets: 0, ctrl_offset: 0, size: 0, align };
+        }
+
+        let ctrl_offset = (size_of::<T>() * buckets + align - 1) & !(align - 1);
+        TableLayout {
+            buckets,
+            ctrl_offset,
+            size: ctrl_offset + buckets + group_width,
+            align,
+        }
+    }
+
+    /// Find the `ctrl` pointer of the table among the words of `map` that aren't its `hasher`,
+    /// given the addresses of all of its occupied buckets.
+    unsafe fn find_ctrl<M, S, T, I>(&self, map: &M, hasher: &S, buckets: I) -> Option<usize>
+        where I: Iterator<Item = *const T> + Clone
+    {
+        let bucket_size = size_of::<T>();
+        if GROUP_WIDTH.is_none() || self.buckets == 0 || bucket_size == 0 {
+            return None;
+        }
+
+        let table_size = TABLE_WORDS * size_of::<usize>();
+        if size_of::<M>() != size_of::<S>() + table_size {
+            return None;
+        }
+        let hasher_offset = hasher as *const S as usize - map as *const M as usize;
+        let table_offset = if hasher_offset == 0 {
+            size_of::<S>()
+        } else if hasher_offset == table_size {
+            0
+        } else {
+            return None;
+        };
+        if !table_offset.is_multiple_of(align_of::<usize>()) {
+            return None;
+        }
+
+        let words = (map as *const M as *const u8).add(table_offset) as *const usize;
+        let words = (0..TABLE_WORDS).map(|i| words.add(i).read());
+
+        // The table stores its bucket mask next to the pointer; insist on finding it too.
+        if !words.clone().any(|word| word == self.buckets - 1) {
+            return None;
+        }
+
+        let data_size = self.buckets * bucket_size;
+        let mut candidates = words.filter(|&ctrl| ctrl.is_multiple_of(self.align) && ctrl > data_size);
+        candidates.find(|&ctrl| {
+            let mut buckets = buckets.clone().peekable();
+            // Without a bucket to check against we can't tell the pointer from any other word.
+            buckets.peek().is_some() && buckets.all(|bucket| {
+                let bucket = bucket as usize;
+                bucket < ctrl && ctrl - bucket <= data_size && (ctrl - bucket).is_multiple_of(bucket_size)
+            })
+        })
+    }
+
+    /// Measure the heap block of the table inside `map`, given its `hasher` and the addresses of
+    /// all of its occupied buckets.
+    ///
+    /// # Safety
+    ///
+    /// `map` must be a std `HashMap` or `HashSet` whose `capacity()` this layout was created
+    /// from, with buckets of type `T`, and `hasher` must be the one it holds.
+    pub unsafe fn size_of<M, S, T, I>(&self, map: &M, hasher: &S, buckets: I, ops: &mut SizeOps)
+                                      -> usize
+        where I: Iterator<Item = *const T> + Clone
+    {
+        let layout = Layout::from_size_align(self.size, self.align).unwrap();
+        if ops.estimates() {
+            return ops.estimate_block(layout);
+        }
+        match self.find_ctrl(map, hasher, buckets) {
+            Some(ctrl) => ops.heap_size_of_block((ctrl - self.ctrl_offset) as *const c_void, layout),
+            None => ops.estimate_block(layout),
+        }
+    }
+}
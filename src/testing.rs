@@ -0,0 +1,177 @@
+//! Tools for checking `HeapSizeOf` implementations against what was actually allocated.
+//!
+//! Install `TrackingAllocator` as the global allocator of a test binary, then build values with
+//! `assert_measurement_matches`:
+//!
+//! ```no_run
+//! extern crate heapsize;
+//!
+//! use heapsize::testing::{TrackingAllocator, assert_measurement_matches};
+//!
+//! #[global_allocator]
+//! static ALLOCATOR: TrackingAllocator = TrackingAllocator::new();
+//!
+//! fn main() {
+//!     assert_measurement_matches(|| vec![Box::new(1), Box::new(2)]);
+//! }
+//! ```
+
+use std::alloc::{GlobalAlloc, Layout, System};
+use std::cell::Cell;
+use std::fmt;
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+
+use {HeapSizeOf, SystemProvider, UsableSizeProvider};
+
+static INSTALLED: AtomicBool = AtomicBool::new(false);
+static LIVE_BYTES: AtomicUsize = AtomicUsize::new(0);
+
+thread_local! {
+    // The usable size of the blocks allocated on this thread, minus those freed on it.
+    static THREAD_LIVE_BYTES: Cell<isize> = const { Cell::new(0) };
+}
+
+/// A global allocator that keeps track of the usable size of the blocks it has handed out.
+///
+/// Sizes are taken from its own `UsableSizeProvider`, which must describe the allocator being
+/// wrapped (`SystemProvider` for `System` by default). The provider registered with
+/// `set_usable_size_provider` isn't used, since the runtime allocates before anything can be
+/// registered.
+pub struct TrackingAllocator<A = System, P = SystemProvider> {
+    inner: A,
+    provider: P,
+}
+
+impl TrackingAllocator<System, SystemProvider> {
+    /// Track allocations made with the system allocator.
+    pub const fn new() -> TrackingAllocator<System, SystemProvider> {
+        TrackingAllocator { inner: System, provider: SystemProvider }
+    }
+}
+
+impl Default for TrackingAllocator<System, SystemProvider> {
+    fn default() -> TrackingAllocator<System, SystemProvider> {
+        TrackingAllocator::new()
+    }
+}
+
+impl<A, P> TrackingAllocator<A, P> {
+    /// Track allocations made with another allocator, whose blocks `provider` can measure.
+    ///
+    /// Measurements still go through the registered provider, so register the same one with
+    /// `set_usable_size_provider` for them to agree.
+    pub const fn with_allocator(inner: A, provider: P) -> TrackingAllocator<A, P> {
+        TrackingAllocator { inner, provider }
+    }
+}
+
+/// The usable size of all the blocks currently allocated through `TrackingAllocator`, on any
+/// thread.
+pub fn live_bytes() -> usize {
+    LIVE_BYTES.load(Ordering::Relaxed)
+}
+
+fn record(size: usize, allocated: bool) {
+    INSTALLED.store(true, Ordering::Relaxed);
+    let delta = if allocated {
+        LIVE_BYTES.fetch_add(size, Ordering::Relaxed);
+        size as isize
+    } else {
+        LIVE_BYTES.fetch_sub(size, Ordering::Relaxed);
+        -(size as isize)
+    };
+    // Blocks freed while the thread is shutting down don't matter any more.
+    let _ = THREAD_LIVE_BYTES.try_with(|live| live.set(live.get() + delta));
+}
+
+impl<A, P: UsableSizeProvider> TrackingAllocator<A, P> {
+    unsafe fn usable_size(&self, ptr: *mut u8) -> usize {
+        self.provider.usable_size(ptr as *const c_void)
+    }
+}
+
+unsafe impl<A: GlobalAlloc, P: UsableSizeProvider> GlobalAlloc for TrackingAllocator<A, P> {
+    unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc(layout);
+        if !ptr.is_null() {
+            record(self.usable_size(ptr), true);
+        }
+        ptr
+    }
+
+    unsafe fn alloc_zeroed(&self, layout: Layout) -> *mut u8 {
+        let ptr = self.inner.alloc_zeroed(layout);
+        if !ptr.is_null() {
+            record(self.usable_size(ptr), true);
+        }
+        ptr
+    }
+
+    unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+        record(self.usable_size(ptr), false);
+        self.inner.dealloc(ptr, layout)
+    }
+
+    unsafe fn realloc(&self, ptr: *mut u8, layout: Layout, new_size: usize) -> *mut u8 {
+        let old_size = self.usable_size(ptr);
+        let new_ptr = self.inner.realloc(ptr, layout, new_size);
+        if !new_ptr.is_null() {
+            record(old_size, false);
+            record(self.usable_size(new_ptr), true);
+        }
+        new_ptr
+    }
+}
+
+/// How a measurement of a freshly built value compares to what building it allocated.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Measurement {
+    /// The usable size of the blocks allocated while building the value and still live.
+    pub allocated: usize,
+    /// What `heap_size_of_children` says about the value.
+    pub measured: usize,
+}
+
+impl Measurement {
+    /// How many bytes were measured but not allocated; negative if some were missed.
+    pub fn delta(&self) -> isize {
+        self.measured as isize - self.allocated as isize
+    }
+}
+
+impl fmt::Display for Measurement {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "measured {} bytes but {} were allocated (delta {:+})",
+               self.measured, self.allocated, self.delta())
+    }
+}
+
+/// Build a value and compare what it allocated (net of anything freed while building it) with
+/// its `heap_size_of_children`.
+///
+/// Panics if `TrackingAllocator` isn't the global allocator. Only allocations made on the current
+/// thread are counted.
+pub fn check_measurement<T: HeapSizeOf, F: FnOnce() -> T>(build: F) -> (T, Measurement) {
+    let before = thread_live_bytes();
+    let value = build();
+    let after = thread_live_bytes();
+    assert!(INSTALLED.load(Ordering::Relaxed),
+            "heapsize::testing::TrackingAllocator is not the global allocator");
+    let measurement = Measurement {
+        allocated: (after - before).max(0) as usize,
+        measured: value.heap_size_of_children(),
+    };
+    (value, measurement)
+}
+
+/// Like `check_measurement`, but panics if the measurement doesn't match what was allocated.
+pub fn assert_measurement_matches<T: HeapSizeOf, F: FnOnce() -> T>(build: F) -> T {
+    let (value, measurement) = check_measurement(build);
+    assert!(measurement.delta() == 0, "{}", measurement);
+    value
+}
+
+fn thread_live_bytes() -> isize {
+    THREAD_LIVE_BYTES.with(|live| live.get())
+}
@@ -0,0 +1,162 @@
+//! Checking that a pointer can be the start of a heap block before measuring it.
+//!
+//! Asking the allocator about a pointer it doesn't own crashes or returns garbage. Where the
+//! allocator can tell us whether it owns a pointer we ask it; otherwise we rule out the places
+//! measured pointers most often wrongly point to: the current thread's stack, and the static
+//! memory of the executable and the libraries it has loaded.
+
+use std::error::Error;
+use std::fmt;
+use std::mem::align_of;
+use std::ffi::c_void;
+
+use usable_size_provider;
+
+/// Why a pointer can't be measured with `heap_size_of`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum HeapSizeError {
+    /// The pointer isn't aligned like the start of any heap block.
+    Misaligned,
+    /// The pointer is into the current thread's stack.
+    Stack,
+    /// The pointer is into the static memory of the executable or of a loaded library.
+    Static,
+    /// The allocator says the pointer isn't the start of one of its blocks.
+    NotOwned,
+}
+
+impl fmt::Display for HeapSizeError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(match *self {
+            HeapSizeError::Misaligned => "pointer is not aligned like a heap block",
+            HeapSizeError::Stack => "pointer is into the stack",
+            HeapSizeError::Static => "pointer is into static memory",
+            HeapSizeError::NotOwned => "pointer is not owned by the allocator",
+        })
+    }
+}
+
+impl Error for HeapSizeError {}
+
+/// Check that `ptr` (which isn't null or dangling) may be the start of a heap block.
+pub fn validate(ptr: *const c_void) -> Result<(), HeapSizeError> {
+    if !(ptr as usize).is_multiple_of(align_of::<usize>()) {
+        return Err(HeapSizeError::Misaligned);
+    }
+    match unsafe { usable_size_provider().owns(ptr) } {
+        Some(true) => return Ok(()),
+        Some(false) => return Err(HeapSizeError::NotOwned),
+        None => {}
+    }
+    if is_on_stack(ptr) {
+        return Err(HeapSizeError::Stack);
+    }
+    if is_static(ptr) {
+        return Err(HeapSizeError::Static);
+    }
+    Ok(())
+}
+
+#[cfg(all(feature = "std", any(target_os = "linux", target_os = "android")))]
+mod platform {
+    use std::mem::MaybeUninit;
+    use std::ptr;
+    use std::os::raw::{c_char, c_int, c_void};
+
+    // Big enough for `pthread_attr_t` on every Linux target.
+    #[repr(C, align(8))]
+    struct PthreadAttr([u8; 128]);
+
+    #[cfg(target_pointer_width = "64")]
+    #[repr(C)]
+    struct ProgramHeader {
+        p_type: u32,
+        p_flags: u32,
+        p_offset: u64,
+        p_vaddr: u64,
+        p_paddr: u64,
+        p_filesz: u64,
+        p_memsz: u64,
+        p_align: u64,
+    }
+
+    #[cfg(target_pointer_width = "32")]
+    #[repr(C)]
+    struct ProgramHeader {
+        p_type: u32,
+        p_offset: u32,
+        p_vaddr: u32,
+        p_paddr: u32,
+        p_filesz: u32,
+        p_memsz: u32,
+        p_flags: u32,
+        p_align: u32,
+    }
+
+    const PT_LOAD: u32 = 1;
+
+    #[repr(C)]
+    struct DlPhdrInfo {
+        dlpi_addr: usize,
+        dlpi_name: *const c_char,
+        dlpi_phdr: *const ProgramHeader,
+        dlpi_phnum: u16,
+    }
+
+    extern "C" {
+        fn pthread_self() -> usize;
+        fn pthread_getattr_np(thread: usize, attr: *mut PthreadAttr) -> c_int;
+        fn pthread_attr_getstack(attr: *const PthreadAttr, addr: *mut *mut c_void,
+                                 size: *mut usize) -> c_int;
+        fn pthread_attr_destroy(attr: *mut PthreadAttr) -> c_int;
+        fn dl_iterate_phdr(callback: extern "C" fn(*mut DlPhdrInfo, usize, *mut c_void) -> c_int,
+                           data: *mut c_void) -> c_int;
+    }
+
+    pub fn is_on_stack(ptr: *const c_void) -> bool {
+        unsafe {
+            let mut attr = MaybeUninit::<PthreadAttr>::uninit();
+            if pthread_getattr_np(pthread_self(), attr.as_mut_ptr()) != 0 {
+                return false;
+            }
+            let mut addr = ptr::null_mut();
+            let mut size = 0;
+            let found = pthread_attr_getstack(attr.as_ptr(), &mut addr, &mut size) == 0;
+            pthread_attr_destroy(attr.as_mut_ptr());
+            let (start, ptr) = (addr as usize, ptr as usize);
+            found && start <= ptr && ptr < start + size
+        }
+    }
+
+    extern "C" fn check_object(info: *mut DlPhdrInfo, _: usize, ptr: *mut c_void) -> c_int {
+        let info = unsafe { &*info };
+        let ptr = ptr as usize;
+        let found = (0..info.dlpi_phnum as usize).any(|i| {
+            let header = unsafe { &*info.dlpi_phdr.add(i) };
+            let start = info.dlpi_addr.wrapping_add(header.p_vaddr as usize);
+            header.p_type == PT_LOAD && start <= ptr && ptr - start < header.p_memsz as usize
+        });
+        found as c_int
+    }
+
+    pub fn is_static(ptr: *const c_void) -> bool {
+        unsafe { dl_iterate_phdr(check_object, ptr as *mut c_void) != 0 }
+    }
+}
+
+#[cfg(not(all(feature = "std", any(target_os = "linux", target_os = "android"))))]
+mod platform {
+    use std::ffi::c_void;
+
+    // Without a way to find the stack and loaded images, we can only go by what the allocator
+    // says.
+    pub fn is_on_stack(_: *const c_void) -> bool {
+        false
+    }
+
+    pub fn is_static(_: *const c_void) -> bool {
+        false
+    }
+}
+
+use self::platform::{is_on_stack, is_static};
@@ -0,0 +1,97 @@
+extern crate heapsize;
+
+use heapsize::about_memory::{Kind, MemoryReports, Report, Units};
+use heapsize::{HeapSizeOf, Reporter, SizeOps};
+use std::os::raw::c_void;
+
+unsafe fn one_per_block(_: *const c_void) -> usize {
+    1
+}
+
+#[test]
+fn test_round_trip() {
+    let mut reports = MemoryReports::new();
+    reports.reports.push(Report {
+        process: "Main Process (pid 1)".to_owned(),
+        path: "explicit/a/b".to_owned(),
+        kind: Kind::Heap,
+        units: Units::Bytes,
+        amount: 4096,
+        description: "Quotes \" and backslashes \\, a\nnewline and \u{1} and caf\u{e9}.".to_owned(),
+    });
+    reports.reports.push(Report {
+        process: "Main Process (pid 1)".to_owned(),
+        path: "vsize".to_owned(),
+        kind: Kind::Other,
+        units: Units::Bytes,
+        amount: -1,
+        description: String::new(),
+    });
+
+    let json = reports.to_json();
+    assert_eq!(MemoryReports::from_json(&json).unwrap(), reports);
+}
+
+#[test]
+fn test_read() {
+    // Formatted the way Firefox writes it.
+    let json = r#"{
+      "version": 1,
+      "hasMozMallocUsableSize": true,
+      "reports": [
+        {
+          "process": "Main Process (pid 42)",
+          "path": "explicit/images/é",
+          "kind": 1,
+          "units": 0,
+          "amount": 1.2e3,
+          "description": "Images."
+        }
+      ]
+    }"#;
+    let reports = MemoryReports::from_json(json).unwrap();
+    assert!(reports.has_moz_malloc_usable_size);
+    assert_eq!(reports.reports.len(), 1);
+    assert_eq!(reports.reports[0].path, "explicit/images/\u{e9}");
+    assert_eq!(reports.reports[0].amount, 1200);
+
+    assert!(MemoryReports::from_json("{\"version\": 1}").is_err());
+    assert!(MemoryReports::from_json("{\"version\": 1, \"reports\": [").is_err());
+    assert!(MemoryReports::from_json(&json.replace("\"kind\": 1", "\"kind\": 7")).is_err());
+
+    // Surrogate pairs are decoded, and broken ones rejected.
+    let path = |path: &str| json.replace("explicit/images/é", path);
+    let reports = MemoryReports::from_json(&path("\\ud83d\\ude00")).unwrap();
+    assert_eq!(reports.reports[0].path, "\u{1f600}");
+    assert!(MemoryReports::from_json(&path("\\ud83d\\u0041")).is_err());
+    assert!(MemoryReports::from_json(&path("\\ude00")).is_err());
+
+    // Nesting is fine up to a point, but too much of it is an error rather than a stack overflow.
+    let nested = |depth| json.replace("\"version\": 1,", &format!(
+        "\"version\": 1, \"extra\": {}{},", "[".repeat(depth), "]".repeat(depth)));
+    assert!(MemoryReports::from_json(&nested(100)).is_ok());
+    let error = MemoryReports::from_json(&nested(100_000)).unwrap_err();
+    assert!(error.to_string().contains("too deeply nested"), "{}", error);
+}
+
+#[test]
+fn test_from_tree() {
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    vec![Box::new(0u8)].report_heap_size("cache/entries", &mut reporter);
+    reporter.report("cache", 3);
+    reporter.report("other", 5);
+    let tree = reporter.finish();
+
+    let mut reports = MemoryReports::new();
+    reports.add_tree("Main Process", "explicit/", &tree);
+    let paths: Vec<_> = reports.reports.iter().map(|r| (&*r.path, r.amount)).collect();
+    assert_eq!(paths, [
+        ("explicit/cache/own", 3),
+        ("explicit/cache/entries", 2),
+        ("explicit/other", 5),
+    ]);
+    assert!(reports.reports.iter().all(|r| r.kind == Kind::Heap && r.units == Units::Bytes));
+
+    let json = reports.to_json();
+    assert_eq!(MemoryReports::from_json(&json).unwrap(), reports);
+}
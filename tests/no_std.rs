@@ -0,0 +1,52 @@
+// What works without the `std` feature: collections from `alloc`, estimates and custom size
+// functions, since there is no system allocator to ask.
+
+extern crate heapsize;
+
+use heapsize::{HeapSizeOf, Reporter, SharedPolicy, SizeClassModel, SizeOps};
+use std::cell::RefCell;
+use std::collections::{BTreeMap, VecDeque};
+use std::os::raw::c_void;
+use std::rc::Rc;
+
+unsafe fn one_per_block(_: *const c_void) -> usize {
+    1
+}
+
+#[test]
+fn test_alloc_types() {
+    let x = vec![Box::new(0u64), Box::new(1u64)];
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::with_size_of_op(one_per_block)), 3);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 2 * 8 + 2 * 8);
+
+    let x = String::from("abc");
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 3);
+    let x: VecDeque<u32> = VecDeque::with_capacity(5);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), x.capacity() * 4);
+    let x: BTreeMap<u64, u64> = (0..20).map(|i| (i, i)).collect();
+    assert!(x.heap_size_of_children_with(&mut SizeOps::estimate()) > 20 * 16);
+    let x = RefCell::new(vec![0u8; 16]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+
+    let x = Rc::new(vec![0u8; 16]);
+    let mut ops = SizeOps::estimate().with_shared_policy(SharedPolicy::Always);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 2 * 8 + 24 + 16);
+}
+
+#[test]
+fn test_estimate_with_model() {
+    let x = vec![0u8; 20];
+    let mut ops = SizeOps::estimate_with_model(SizeClassModel::Jemalloc);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 32);
+    assert_eq!(ops.slop(), 12);
+}
+
+#[test]
+fn test_report() {
+    let mut reporter = Reporter::with_ops(SizeOps::estimate());
+    vec![0u8; 10].report_heap_size("a", &mut reporter);
+    reporter.report("b", 5);
+    let report = reporter.finish();
+    assert_eq!(report.size(), 15);
+    assert_eq!(report.get("a").unwrap().size(), 10);
+}
@@ -0,0 +1,61 @@
+// Registering a provider is process-wide, so this lives in its own test binary.
+
+extern crate heapsize;
+
+use heapsize::{HeapSizeOf, SystemProvider, UsableSizeProvider};
+use std::os::raw::c_void;
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+struct CountingProvider {
+    calls: AtomicUsize,
+}
+
+impl UsableSizeProvider for CountingProvider {
+    unsafe fn usable_size(&self, ptr: *const c_void) -> usize {
+        self.calls.fetch_add(1, Ordering::SeqCst);
+        SystemProvider.usable_size(ptr)
+    }
+}
+
+static PROVIDER: CountingProvider = CountingProvider { calls: AtomicUsize::new(0) };
+
+#[test]
+fn test_registered_provider() {
+    heapsize::set_usable_size_provider(&PROVIDER).unwrap();
+    assert!(heapsize::set_usable_size_provider(&SystemProvider).is_err());
+
+    let x = vec![Box::new(0u64), Box::new(1u64)];
+    let expected = unsafe {
+        SystemProvider.usable_size(x.as_ptr() as *const c_void) +
+            SystemProvider.usable_size(&*x[0] as *const u64 as *const c_void) +
+            SystemProvider.usable_size(&*x[1] as *const u64 as *const c_void)
+    };
+    assert_eq!(x.heap_size_of_children(), expected);
+    assert_eq!(PROVIDER.calls.load(Ordering::SeqCst), 3);
+}
+
+// The tracking allocator measures blocks with its own provider, which works before anything is
+// registered and whatever the default provider is.
+#[test]
+fn test_tracking_allocator_provider() {
+    use heapsize::testing::{TrackingAllocator, live_bytes};
+    use std::alloc::{GlobalAlloc, Layout, System};
+
+    struct HundredProvider;
+
+    impl UsableSizeProvider for HundredProvider {
+        unsafe fn usable_size(&self, _: *const c_void) -> usize {
+            100
+        }
+    }
+
+    let allocator = TrackingAllocator::with_allocator(System, HundredProvider);
+    unsafe {
+        let ptr = allocator.alloc(Layout::new::<u64>());
+        assert_eq!(live_bytes(), 100);
+        let ptr = allocator.realloc(ptr, Layout::new::<u64>(), 16);
+        assert_eq!(live_bytes(), 100);
+        allocator.dealloc(ptr, Layout::new::<[u64; 2]>());
+    }
+    assert_eq!(live_bytes(), 0);
+}
@@ -1,132 +1,39 @@
-#![cfg_attr(feature= "unstable", feature(allocator_api, repr_simd))]
-
 extern crate heapsize;
 
-use heapsize::{HeapSizeOf, heap_size_of};
+use heapsize::{HeapSize, HeapSizeError, HeapSizeOf, Reporter, SharedPolicy, SizeClassModel};
+use heapsize::SizeOps;
+use heapsize::{heap_size_of, try_heap_size_of};
+use heapsize::testing::{TrackingAllocator, assert_measurement_matches, check_measurement};
+use std::os::raw::c_void;
+use std::rc::Rc;
+use std::sync::Arc;
 
-/// https://github.com/servo/heapsize/issues/74
-#[cfg(feature = "flexible-tests")]
-macro_rules! assert_size {
-    ($actual: expr, $expected: expr) => {
-        {
-            let actual = $actual;
-            let expected = $expected;
-            assert!(actual >= expected, "expected {:?} >= {:?}", actual, expected)
-        }
-    }
-}
+#[global_allocator]
+static ALLOCATOR: TrackingAllocator = TrackingAllocator::new();
 
-#[cfg(not(feature = "flexible-tests"))]
-macro_rules! assert_size {
-    ($actual: expr, $expected: expr) => {
-        assert_eq!($actual, $expected)
-    }
-}
-
-#[cfg(feature = "unstable")]
-mod unstable {
-    use heapsize::heap_size_of;
-    use std::os::raw::c_void;
-    use std::heap::{Heap, Alloc, Layout};
-
-    unsafe fn allocate(size: usize, align: usize) -> *mut u8 {
-        Heap.alloc(Layout::from_size_align(size, align).unwrap()).unwrap()
-    }
-
-    unsafe fn deallocate(ptr: *mut u8, size: usize, align: usize) {
-        Heap.dealloc(ptr, Layout::from_size_align(size, align).unwrap())
-    }
-
-    #[repr(simd)]
-    struct OverAligned(u64, u64, u64, u64);
-
-    #[cfg(not(target_os = "windows"))]
-    #[test]
-    fn test_alloc() {
-        unsafe {
-            // A 64 byte request is allocated exactly.
-            let x = allocate(64, 1);
-            assert_size!(heap_size_of(x as *const c_void), 64);
-            deallocate(x, 64, 1);
-
-            // A 255 byte request is rounded up to 256 bytes.
-            let x = allocate(255, 1);
-            assert_size!(heap_size_of(x as *const c_void), 256);
-            deallocate(x, 255, 1);
-
-            // A 1MiB request is allocated exactly.
-            let x = allocate(1024 * 1024, 1);
-            assert_size!(heap_size_of(x as *const c_void), 1024 * 1024);
-            deallocate(x, 1024 * 1024, 1);
-
-            // An overaligned 1MiB request is allocated exactly.
-            let x = allocate(1024 * 1024, 32);
-            assert_size!(heap_size_of(x as *const c_void), 1024 * 1024);
-            deallocate(x, 1024 * 1024, 32);
-        }
-    }
-
-    #[cfg(target_os = "windows")]
-    #[test]
-    fn test_alloc() {
-        unsafe {
-            // A 64 byte request is allocated exactly.
-            let x = allocate(64, 1);
-            assert_size!(heap_size_of(x as *const c_void), 64);
-            deallocate(x, 64, 1);
-
-            // A 255 byte request is allocated exactly.
-            let x = allocate(255, 1);
-            assert_size!(heap_size_of(x as *const c_void), 255);
-            deallocate(x, 255, 1);
-
-            // A 1MiB request is allocated exactly.
-            let x = allocate(1024 * 1024, 1);
-            assert_size!(heap_size_of(x as *const c_void), 1024 * 1024);
-            deallocate(x, 1024 * 1024, 1);
-
-            // An overaligned 1MiB request is over-allocated.
-            let x = allocate(1024 * 1024, 32);
-            assert_size!(heap_size_of(x as *const c_void), 1024 * 1024 + 32);
-            deallocate(x, 1024 * 1024, 32);
-        }
-    }
-
-    #[cfg(not(target_os = "windows"))]
-    #[test]
-    fn test_simd() {
-        let x = Box::new(OverAligned(0, 0, 0, 0));
-        assert_size!(unsafe { heap_size_of(&*x as *const _ as *const c_void) }, 32);
-    }
-
-    #[cfg(target_os = "windows")]
-    #[test]
-    fn test_simd() {
-        let x = Box::new(OverAligned(0, 0, 0, 0));
-        assert_size!(unsafe { heap_size_of(&*x as *const _ as *const c_void) }, 32 + 32);
-    }
+// Count every heap block as one byte, so that results don't depend on the allocator.
+unsafe fn one_per_block(_: *const c_void) -> usize {
+    1
 }
 
 #[test]
 fn test_boxed_str() {
-    let x = "raclette".to_owned().into_boxed_str();
-    assert_size!(x.heap_size_of_children(), 8);
+    assert_measurement_matches(|| "raclette".to_owned().into_boxed_str());
 }
 
 #[test]
 fn test_heap_size() {
 
-    // Note: jemalloc often rounds up request sizes. However, it does not round up for request
-    // sizes of 8 and higher that are powers of two. We take advantage of knowledge here to make
-    // the sizes of various heap-allocated blocks predictable.
+    // Sizes depend on how the allocator rounds up requests, so rather than hard-coding them we
+    // check that measurements match what was allocated while building each value.
 
     //-----------------------------------------------------------------------
     // Start with basic heap block measurement.
 
     unsafe {
         // EMPTY is the special non-null address used to represent zero-size allocations.
-        assert_size!(heap_size_of::<[u64; 0]>(&*Box::new([42_u64; 0])), 0);
-        assert_size!(heap_size_of::<[u8; 0]>(&*Box::new([42_u8; 0])), 0);
+        assert_eq!(heap_size_of::<[u64; 0]>(&*Box::new([42_u64; 0])), 0);
+        assert_eq!(heap_size_of::<[u8; 0]>(&*Box::new([42_u8; 0])), 0);
     }
 
     //-----------------------------------------------------------------------
@@ -134,53 +41,856 @@ fn test_heap_size() {
 
     // Not on the heap; 0 bytes.
     let x = 0i64;
-    assert_size!(x.heap_size_of_children(), 0);
+    assert_eq!(x.heap_size_of_children(), 0);
 
     // An i64 is 8 bytes.
-    let x = Box::new(0i64);
-    assert_size!(x.heap_size_of_children(), 8);
+    let x = assert_measurement_matches(|| Box::new(0i64));
+    assert!(x.heap_size_of_children() >= 8);
 
     // An ascii string with 16 chars is 16 bytes in UTF-8.
-    let string = String::from("0123456789abcdef");
-    assert_size!(string.heap_size_of_children(), 16);
+    let string = assert_measurement_matches(|| String::from("0123456789abcdef"));
+    assert!(string.heap_size_of_children() >= 16);
 
     let string_ref: (&String, ()) = (&string, ());
-    assert_size!(string_ref.heap_size_of_children(), 0);
+    assert_eq!(string_ref.heap_size_of_children(), 0);
 
-    let slice: &str = &*string;
-    assert_size!(slice.heap_size_of_children(), 0);
+    let slice: &str = &string;
+    assert_eq!(slice.heap_size_of_children(), 0);
 
     // Not on the heap.
     let x: Option<i32> = None;
-    assert_size!(x.heap_size_of_children(), 0);
+    assert_eq!(x.heap_size_of_children(), 0);
 
     // Not on the heap.
     let x = Some(0i64);
-    assert_size!(x.heap_size_of_children(), 0);
+    assert_eq!(x.heap_size_of_children(), 0);
 
     // The `Some` is not on the heap, but the Box is.
-    let x = Some(Box::new(0i64));
-    assert_size!(x.heap_size_of_children(), 8);
+    assert_measurement_matches(|| Some(Box::new(0i64)));
 
-    // Not on the heap.
+    // Not measured: the `Arc` block may be shared.
     let x = ::std::sync::Arc::new(0i64);
-    assert_size!(x.heap_size_of_children(), 0);
+    assert_eq!(x.heap_size_of_children(), 0);
 
-    // The `Arc` is not on the heap, but the Box is.
+    // The `Arc` block is not measured, but the Box is.
     let x = ::std::sync::Arc::new(Box::new(0i64));
-    assert_size!(x.heap_size_of_children(), 8);
+    assert_eq!(x.heap_size_of_children(), unsafe { heap_size_of::<i64>(&**x) });
 
     // Zero elements, no heap storage.
-    let x: Vec<i64> = vec![];
-    assert_size!(x.heap_size_of_children(), 0);
+    let x: Vec<i64> = assert_measurement_matches(Vec::new);
+    assert_eq!(x.heap_size_of_children(), 0);
 
     // Four elements, 8 bytes per element.
-    let x = vec![0i64, 1i64, 2i64, 3i64];
-    assert_size!(x.heap_size_of_children(), 32);
+    let x = assert_measurement_matches(|| vec![0i64, 1i64, 2i64, 3i64]);
+    assert!(x.heap_size_of_children() >= 32);
 }
 
 #[test]
 fn test_boxed_slice() {
-    let x = vec![1i64, 2i64].into_boxed_slice();
-    assert_size!(x.heap_size_of_children(), 16)
+    assert_measurement_matches(|| vec![1i64, 2i64].into_boxed_slice());
+}
+
+#[test]
+fn test_check_measurement() {
+    // A broken implementation that forgets the buffer.
+    struct Names(Vec<String>);
+
+    impl HeapSizeOf for Names {
+        fn heap_size_of_children(&self) -> usize {
+            self.0.iter().map(|name| name.heap_size_of_children()).sum()
+        }
+    }
+
+    let (x, measurement) = check_measurement(|| {
+        Names(vec!["a".to_owned(), "b".to_owned()])
+    });
+    assert_eq!(measurement.delta(), -(unsafe { heap_size_of(x.0.as_ptr()) } as isize));
+
+    assert_measurement_matches(|| vec![Some(Box::new(1u8)), None, Some(Box::new(2u8))]);
+    assert_measurement_matches(|| (0..100).map(|i| i.to_string()).collect::<Vec<_>>());
+}
+
+#[test]
+fn test_size_ops() {
+    // Every heap block, including the vector's buffer, counts as a single byte.
+    let x = vec![Some(Box::new(0i64)), None, Some(Box::new(1i64))];
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 3);
+
+    // The default measurement agrees with `heap_size_of_children`.
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::new()), x.heap_size_of_children());
+}
+
+#[test]
+fn test_shared_seen_set() {
+    // Without a seen set, each pointer measures what hangs off the shared value but not the
+    // reference-counted block: one buffer plus the Box three times.
+    let shared = Rc::new(Box::new(0i64));
+    let x = vec![shared.clone(), shared.clone(), shared];
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::with_size_of_op(one_per_block)), 4);
+
+    // With a seen set, the block and its Box are counted once: buffer, Rc block and Box.
+    let mut ops = SizeOps::with_size_of_op(one_per_block).with_seen_set();
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 3);
+
+    // Measuring again within the same traversal doesn't count the shared block again.
+    assert_eq!(x[0].heap_size_of_children_with(&mut ops), 0);
+
+    let shared = Arc::new(vec![0u8; 16]);
+    let x = (shared.clone(), shared);
+    let mut ops = SizeOps::with_size_of_op(one_per_block).with_seen_set();
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 2);
+}
+
+#[test]
+fn test_shared_block_size() {
+    // The reference-counted block holds two counts as well as the value.
+    let x = Rc::new(0u64);
+    let size = x.heap_size_of_children_with(&mut SizeOps::new().with_seen_set());
+    assert!(size >= 3 * 8, "{} is too small for an Rc<u64> block", size);
+
+    let x = Arc::new((0u64, 0u64, 0u64, 0u64));
+    let size = x.heap_size_of_children_with(&mut SizeOps::new().with_seen_set());
+    assert!(size >= 6 * 8, "{} is too small for an Arc<(u64, u64, u64, u64)> block", size);
+}
+
+#[test]
+fn test_shared_policy() {
+    use std::rc::Weak;
+
+    // Each Rc<u64> block holds two counts and the value, and the value's Box holds 8 bytes.
+    let shared = Rc::new(Box::new(0u64));
+    let x = (shared.clone(), shared);
+    let measure = |policy| {
+        let mut ops = SizeOps::estimate().with_shared_policy(policy);
+        x.heap_size_of_children_with(&mut ops)
+    };
+    assert_eq!(measure(SharedPolicy::Skip), 2 * 8);
+    assert_eq!(measure(SharedPolicy::Always), 2 * (24 + 8));
+    assert_eq!(measure(SharedPolicy::Once), 24 + 8);
+    assert_eq!(SizeOps::new().with_seen_set().shared_policy(), SharedPolicy::Once);
+
+    // Unsized values are counted with their own size.
+    let x: Rc<str> = Rc::from("abc");
+    let mut ops = SizeOps::estimate().with_shared_policy(SharedPolicy::Always);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 24);
+    let x: Arc<str> = Arc::from("abcdefghi");
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 32);
+    let x: Rc<[Box<u8>]> = Rc::from(vec![Box::new(0u8), Box::new(1u8)]);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 16 + 2 * 8 + 2);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 2);
+
+    // Weak pointers count the block only once the value is gone.
+    let strong = Rc::new(0u64);
+    let weak = Rc::downgrade(&strong);
+    let mut ops = SizeOps::estimate().with_shared_policy(SharedPolicy::Always);
+    assert_eq!(weak.heap_size_of_children_with(&mut ops), 0);
+    drop(strong);
+    assert_eq!(weak.heap_size_of_children_with(&mut ops), 24);
+    assert_eq!(weak.heap_size_of_children(), 0);
+    let mut ops = SizeOps::estimate().with_shared_policy(SharedPolicy::Once);
+    assert_eq!((weak.clone(), weak).heap_size_of_children_with(&mut ops), 24);
+    let mut ops = SizeOps::new().with_shared_policy(SharedPolicy::Always);
+    assert_eq!(Weak::<u64>::new().heap_size_of_children_with(&mut ops), 0);
+
+    let strong = Arc::new(0u8);
+    let weak = Arc::downgrade(&strong);
+    drop(strong);
+    let size = weak.heap_size_of_children_with(&mut SizeOps::new().with_seen_set());
+    assert!(size >= 17, "{} is too small for an Arc<u8> block", size);
+}
+
+#[test]
+fn test_locks() {
+    use std::cell::{OnceCell, RefCell};
+    use std::sync::{mpsc, Mutex, OnceLock, RwLock};
+    use std::sync::atomic::AtomicU64;
+    use std::thread;
+
+    // Locks and cells are measured through.
+    let x = Mutex::new(vec![0u8; 16]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+    let x = RwLock::new(vec![0u8; 16]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+    let x = OnceLock::new();
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 0);
+    x.set(vec![0u8; 16]).unwrap();
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+    let x = OnceCell::from(Box::new(0u64));
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 8);
+    assert_eq!(AtomicU64::new(0).heap_size_of_children(), 0);
+
+    // Locks held elsewhere, even on this thread, are reported rather than waited for.
+    let x = Mutex::new(vec![0u8; 16]);
+    let guard = x.lock().unwrap();
+    let mut ops = SizeOps::estimate();
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 0);
+    assert_eq!(ops.contended(), 1);
+    drop(guard);
+
+    let x = RwLock::new(vec![0u8; 16]);
+    let guard = x.write().unwrap();
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 0);
+    assert_eq!(ops.contended(), 2);
+    drop(guard);
+
+    let x = RefCell::new(vec![0u8; 16]);
+    let guard = x.borrow_mut();
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 0);
+    assert_eq!(ops.contended(), 3);
+    drop(guard);
+
+    // Poisoned locks are still measured.
+    let x = Mutex::new(vec![0u8; 16]);
+    thread::scope(|scope| {
+        scope.spawn(|| {
+            let _guard = x.lock().unwrap();
+            panic!("poisoning the lock");
+        }).join().unwrap_err();
+    });
+    assert!(x.is_poisoned());
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+
+    // Messages queued in channels can't be seen, which is recorded for each end.
+    let (sender, receiver) = mpsc::channel();
+    sender.send(vec![0u8; 16]).unwrap();
+    let mut ops = SizeOps::estimate();
+    assert_eq!(sender.heap_size_of_children_with(&mut ops), 0);
+    assert_eq!(receiver.heap_size_of_children_with(&mut ops), 0);
+    assert_eq!(ops.unmeasured(), 2);
+    assert_eq!(ops.contended(), 0);
+}
+
+#[cfg(feature = "lazy")]
+#[test]
+fn test_lazy() {
+    use std::cell::LazyCell;
+    use std::sync::LazyLock;
+
+    // Measuring doesn't force lazy values.
+    let x = LazyLock::new(|| vec![0u8; 16]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 0);
+    LazyLock::force(&x);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+    let x = LazyCell::new(|| vec![0u8; 16]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 0);
+}
+
+#[test]
+fn test_std_types() {
+    use std::cmp::{Ordering, Reverse};
+    use std::ffi::{CString, OsString};
+    use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+    use std::num::{NonZeroU64, Wrapping};
+    use std::path::PathBuf;
+    use std::time::{Duration, Instant, SystemTime};
+
+    // OS strings and paths measure their buffers like `String`.
+    let mut x = OsString::with_capacity(16);
+    x.push("abc");
+    let size = SizeOps::estimate().measure(&x);
+    assert_eq!((size.requested, size.spare), (16, 13));
+    assert_eq!(x.heap_size_of_children(), unsafe { heap_size_of(x.as_encoded_bytes().as_ptr()) });
+    assert_eq!(SizeOps::estimate().measure(&OsString::with_capacity(4)).spare, 4);
+    let x = PathBuf::from("/usr/lib");
+    assert_eq!(SizeOps::estimate().measure(&x).requested, x.capacity());
+    assert_measurement_matches(|| PathBuf::from("/usr/lib"));
+
+    // C strings hold their terminator too.
+    let x = CString::new("abc").unwrap();
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 4);
+    assert_measurement_matches(|| CString::new("abc").unwrap());
+    assert_measurement_matches(|| PathBuf::from("/usr").into_boxed_path());
+    assert_measurement_matches(|| String::from("abc").into_boxed_str());
+
+    // Wrappers measure what they wrap.
+    let x = (Wrapping(Box::new(0u64)), Reverse(Box::new(0u64)));
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16);
+
+    // Plain values have nothing on the heap.
+    assert_eq!(Duration::from_secs(1).heap_size_of_children(), 0);
+    assert_eq!(Instant::now().heap_size_of_children(), 0);
+    assert_eq!(SystemTime::now().heap_size_of_children(), 0);
+    assert_eq!(IpAddr::V4(Ipv4Addr::LOCALHOST).heap_size_of_children(), 0);
+    assert_eq!(SocketAddr::from(([127, 0, 0, 1], 80)).heap_size_of_children(), 0);
+    assert_eq!(NonZeroU64::new(1).heap_size_of_children(), 0);
+    assert_eq!((Ordering::Less, u128::MAX, i128::MIN).heap_size_of_children(), 0);
+}
+
+#[test]
+fn test_arrays_and_tuples() {
+    // Arrays of any length, nested or not, measure each of their elements.
+    let x = [[0u8; 2]; 3].map(|row| row.map(Box::new));
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 6);
+    assert_eq!(x.heap_size_of_children(), 6 * unsafe { heap_size_of(&*x[0][0]) });
+    assert_measurement_matches(|| [Box::new(0u64), Box::new(1)]);
+    assert_eq!([0u64; 100].heap_size_of_children(), 0);
+
+    // So do tuples of up to twelve elements.
+    let x = (Box::new(0u8), 1u8, 2u8, 3u8, 4u8, 5u8, 6u8, 7u8, 8u8, 9u8, 10u8, vec![0u8; 16]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 17);
+    assert_eq!((Box::new(0u32),).heap_size_of_children_with(&mut SizeOps::estimate()), 4);
+}
+
+#[test]
+fn test_trait_object() {
+    trait Shape: HeapSizeOf {}
+
+    struct Polygon(Vec<(f64, f64)>);
+
+    impl HeapSizeOf for Polygon {
+        fn heap_size_of_children(&self) -> usize {
+            self.heap_size_of_children_with(&mut SizeOps::new())
+        }
+
+        fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+            self.0.heap_size_of_children_with(ops)
+        }
+    }
+
+    impl Shape for Polygon {}
+
+    // Boxed trait objects are measured with the size of the value behind them.
+    let x: Box<dyn Shape> = Box::new(Polygon(vec![(0.0, 0.0); 3]));
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 24 + 3 * 16);
+    assert_measurement_matches(|| -> Box<dyn Shape> { Box::new(Polygon(vec![(0.0, 0.0); 3])) });
+
+    // Including ones of different sizes in the same container, and values of no size at all.
+    struct Point;
+    impl HeapSizeOf for Point {
+        fn heap_size_of_children(&self) -> usize { 0 }
+    }
+    impl Shape for Point {}
+    let x: Vec<Box<dyn Shape>> = vec![Box::new(Polygon(vec![(0.0, 0.0)])), Box::new(Point)];
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 2 * 16 + 24 + 16);
+
+    // `HeapSizeOf` itself works as the trait object, with or without auto traits.
+    let x: Box<dyn HeapSizeOf + Send + Sync> = Box::new([Box::new(0u64), Box::new(1)]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 16 + 2 * 8);
+    assert_measurement_matches(|| -> Box<dyn HeapSizeOf> { Box::new(vec![0u32; 5]) });
+    let x: Rc<dyn HeapSizeOf> = Rc::new(String::from("abc"));
+    let mut ops = SizeOps::estimate().with_shared_policy(SharedPolicy::Always);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 16 + 24 + 3);
+
+    // Of a `dyn Any` we can only measure the box.
+    use std::any::Any;
+    let x: Box<dyn Any + Send> = Box::new([0u8; 100]);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 100);
+    assert_measurement_matches(|| -> Box<dyn Any> { Box::new(0u64) });
+}
+
+#[test]
+fn test_estimate() {
+    use std::alloc::Layout;
+    use std::collections::{BTreeMap, HashMap, VecDeque};
+
+    // Estimates are the requested sizes, whatever the allocator does.
+    let x = vec![Box::new(0u64), Box::new(1u64)];
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 2 * 8 + 2 * 8);
+    let mut x = String::with_capacity(3);
+    x.push('a');
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 3);
+    let x: VecDeque<u32> = VecDeque::with_capacity(5);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), x.capacity() * 4);
+
+    // Shared blocks include the reference counts.
+    let x = Rc::new(0u8);
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate().with_seen_set()), 24);
+
+    // Nothing is estimated for empty collections and zero-sized values.
+    let x: Vec<u64> = vec![];
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 0);
+    let x = Box::new(());
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()), 0);
+
+    // A rounding function is applied to every block.
+    fn round_to_16(layout: Layout) -> usize {
+        layout.size().div_ceil(16) * 16
+    }
+    let x = vec![Box::new(0u8), Box::new(1u8)];
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate_with(round_to_16)), 3 * 16);
+
+    // Blocks measured without a layout are estimated from the type they point to.
+    let x = Box::new([0u8; 20]);
+    assert_eq!(unsafe { SizeOps::estimate_with(round_to_16).heap_size_of(&*x) }, 32);
+
+    // Tables and trees have no more than their requested size either.
+    let x: HashMap<u64, u64> = (0..10).map(|i| (i, i)).collect();
+    let size = x.heap_size_of_children_with(&mut SizeOps::estimate());
+    assert!(size >= 16 * 16 + 16 + 4 && size <= x.heap_size_of_children(), "{}", size);
+    let x: BTreeMap<u64, u64> = (0..20).map(|i| (i, i)).collect();
+    assert_eq!(x.heap_size_of_children_with(&mut SizeOps::estimate()),
+               x.heap_size_of_children());
+}
+
+#[test]
+fn test_size_class_model() {
+    use std::alloc::Layout;
+
+    let layout = |size| Layout::from_size_align(size, 1).unwrap();
+
+    // jemalloc rounds 255 bytes up to 256, but allocates 64 bytes and 1MiB exactly.
+    let jemalloc = SizeClassModel::Jemalloc;
+    assert_eq!(jemalloc.usable_size(layout(64)), 64);
+    assert_eq!(jemalloc.usable_size(layout(255)), 256);
+    assert_eq!(jemalloc.usable_size(layout(257)), 320);
+    assert_eq!(jemalloc.usable_size(layout(1024 * 1024)), 1024 * 1024);
+    assert_eq!(jemalloc.usable_size(Layout::from_size_align(1024 * 1024, 32).unwrap()),
+               1024 * 1024);
+    assert_eq!(jemalloc.slop(layout(129)), 31);
+
+    // mimalloc has the same classes above 64 bytes, in words.
+    let mimalloc = SizeClassModel::Mimalloc;
+    assert_eq!(mimalloc.usable_size(layout(1)), 8);
+    assert_eq!(mimalloc.usable_size(layout(20)), 24);
+    assert_eq!(mimalloc.usable_size(layout(33)), 40);
+    assert_eq!(mimalloc.usable_size(layout(72)), 80);
+    assert_eq!(mimalloc.usable_size(layout(255)), 256);
+
+    // The Windows heap allocates exactly, but pads over-aligned blocks.
+    let windows = SizeClassModel::Windows;
+    assert_eq!(windows.usable_size(layout(255)), 255);
+    assert_eq!(windows.usable_size(Layout::from_size_align(1024 * 1024, 32).unwrap()),
+               1024 * 1024 + 32);
+
+    // glibc adds a word to every request and rounds to two words.
+    if cfg!(target_pointer_width = "64") {
+        let glibc = SizeClassModel::Glibc;
+        assert_eq!(glibc.usable_size(layout(1)), 24);
+        assert_eq!(glibc.usable_size(layout(25)), 40);
+        assert_eq!(glibc.slop(layout(255)), 9);
+    }
+
+    // Nothing is allocated for zero-sized blocks.
+    assert_eq!(jemalloc.usable_size(layout(0)), 0);
+
+    // Estimates with a model round every block.
+    let x = vec![Box::new(0u8), Box::new(1u8)];
+    let mut ops = SizeOps::estimate_with_model(SizeClassModel::Jemalloc);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 16 + 2 * 8);
+}
+
+#[test]
+fn test_slop() {
+    use std::collections::BTreeMap;
+
+    // Requested bytes come from capacities and sizes, and the rest of the blocks is slop.
+    let mut ops = SizeOps::estimate_with_model(SizeClassModel::Jemalloc);
+    let x = (Vec::<u8>::with_capacity(129), Box::new(0u8), String::with_capacity(16));
+    assert_eq!(ops.measure(&x), HeapSize { requested: 129 + 1 + 16, slop: 31 + 7, spare: 129 + 16 });
+    assert_eq!(ops.slop(), 38);
+    assert_eq!(ops.measure(&x).total(), 160 + 8 + 16);
+
+    // Nodes we can't measure are estimated, with slop.
+    let x: BTreeMap<u8, u8> = (0..3).map(|i| (i, i)).collect();
+    let size = SizeOps::estimate().measure(&x);
+    assert_eq!(SizeOps::estimate_with_model(SizeClassModel::Jemalloc).measure(&x),
+               HeapSize { requested: size.requested, slop: 48 - size.requested, spare: 0 });
+
+    // Measuring with the allocator gives the same totals as `heap_size_of_children`, with the
+    // capacities as the requested bytes.
+    let x = vec![String::with_capacity(5), String::with_capacity(100)];
+    let size = SizeOps::new().measure(&x);
+    assert_eq!(size.total(), x.heap_size_of_children());
+    assert_eq!(size.requested, 2 * 24 + 5 + 100);
+
+    // Reports keep track of slop at each path.
+    let mut reporter = Reporter::with_ops(SizeOps::estimate_with_model(SizeClassModel::Jemalloc));
+    Box::new(0u8).report_heap_size("a", &mut reporter);
+    Vec::<u8>::with_capacity(16).report_heap_size("b", &mut reporter);
+    reporter.report("b", 3);
+    let report = reporter.finish();
+    assert_eq!(report.get("a").unwrap().own_slop(), 7);
+    assert_eq!(report.get("b").unwrap().own_slop(), 0);
+    assert_eq!(report.slop(), 7);
+}
+
+#[test]
+fn test_spare_capacity() {
+    use std::collections::{HashMap, HashSet, VecDeque};
+
+    // Spare capacity is what the buffer has room for beyond the elements.
+    let mut x = Vec::<u64>::with_capacity(10);
+    x.extend(0..4);
+    let size = SizeOps::estimate().measure(&x);
+    assert_eq!((size.used(), size.spare), (4 * 8, 6 * 8));
+
+    let mut x = String::with_capacity(10);
+    x.push_str("abc");
+    let size = SizeOps::new().measure(&x);
+    assert_eq!((size.used(), size.spare), (3, 7));
+
+    let mut x = VecDeque::<u32>::with_capacity(8);
+    x.extend(0..3);
+    let size = SizeOps::estimate().measure(&x);
+    assert_eq!(size.spare, (x.capacity() - 3) * 4);
+
+    // Elements' own blocks are used in full.
+    let x = vec![String::from("a"), String::from("b")];
+    assert_eq!(SizeOps::estimate().measure(&x).spare, 0);
+
+    // Tables have room for `capacity()` entries.
+    let x: HashMap<u64, u64> = (0..10).map(|i| (i, i)).collect();
+    assert_eq!(SizeOps::new().measure(&x).spare, (x.capacity() - 10) * 16);
+    let x: HashSet<u32> = (0..3).collect();
+    assert_eq!(SizeOps::estimate().measure(&x).spare, (x.capacity() - 3) * 4);
+
+    // Nothing is spare without a buffer.
+    let x: Vec<u64> = Vec::new();
+    assert_eq!(SizeOps::new().measure(&x), HeapSize::default());
+
+    // Reports keep track of spare capacity at each path.
+    let mut reporter = Reporter::with_ops(SizeOps::estimate());
+    Vec::<u8>::with_capacity(16).report_heap_size("a", &mut reporter);
+    vec![0u8; 16].report_heap_size("b", &mut reporter);
+    let report = reporter.finish();
+    assert_eq!(report.get("a").unwrap().own_spare(), 16);
+    assert_eq!(report.get("b").unwrap().own_spare(), 0);
+    assert_eq!(report.spare(), 16);
+}
+
+// The model agrees with the allocator this test runs on, below the size where glibc starts
+// mapping blocks of their own. glibc hands out a free chunk whole rather than leave a remainder
+// too small to be a chunk, so blocks may come out up to that bigger.
+#[cfg(all(target_os = "linux", target_env = "gnu"))]
+#[test]
+fn test_glibc_size_classes() {
+    use std::alloc::{alloc, dealloc, Layout};
+    use std::mem::size_of;
+
+    for &align in &[1, 8, 16] {
+        for size in (1..4096).chain((4096..128 * 1024).step_by(997)) {
+            let layout = Layout::from_size_align(size, align).unwrap();
+            unsafe {
+                let x = alloc(layout);
+                let (size, model) = (heap_size_of(x), SizeClassModel::Glibc.usable_size(layout));
+                assert!(model <= size && size < model + 4 * size_of::<usize>(),
+                        "{:?}: {} for {}", layout, size, model);
+                dealloc(x, layout);
+            }
+        }
+    }
+}
+
+#[test]
+fn test_hash_map() {
+    use std::collections::{HashMap, HashSet};
+
+    // Empty tables don't allocate.
+    let x: HashMap<u64, u64> = HashMap::new();
+    assert_eq!(x.heap_size_of_children(), 0);
+    let x: HashSet<u64> = HashSet::new();
+    assert_eq!(x.heap_size_of_children(), 0);
+
+    // With a capacity of 14 there are 16 buckets of 16 bytes, followed by 16 control bytes and
+    // at least another 4 for the trailing group.
+    let x: HashMap<u64, u64> = (0..10).map(|i| (i, i)).collect();
+    assert_eq!(x.capacity(), 14);
+    assert!(x.heap_size_of_children() >= 16 * 16 + 16 + 4);
+
+    // The values' own heap blocks are measured too.
+    let y: HashMap<u64, Box<u64>> = (0..10).map(|i| (i, Box::new(i))).collect();
+    assert!(y.heap_size_of_children() >= x.heap_size_of_children() + 10 * 8);
+
+    let x: HashSet<u32> = (0..10).collect();
+    assert!(x.heap_size_of_children() >= 16 * 4 + 16 + 4);
+}
+
+#[cfg(any(target_arch = "x86_64", target_arch = "aarch64"))]
+#[test]
+fn test_hash_map_block() {
+    use std::collections::{HashMap, HashSet};
+    use std::hash::{BuildHasher, DefaultHasher, RandomState};
+
+    // The table is found and measured as a single heap block.
+    let x: HashMap<String, u8> = (0..100).map(|i| (i.to_string(), 0)).collect();
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 1 + 100);
+
+    let x: HashSet<(u8, u64)> = (0..100).map(|i| (i, 0)).collect();
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 1);
+
+
+    // With no buckets to go by, the size is computed instead: 128 buckets of 16 bytes, 128
+    // control bytes and a trailing group.
+    let mut x = x;
+    x.clear();
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    let group_width = if cfg!(target_arch = "x86_64") { 16 } else { 8 };
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 128 * 16 + 128 + group_width);
+
+    // The table is found next to a hasher with padding, which isn't read.
+    #[derive(Default)]
+    struct Padded {
+        _tag: u8,
+        state: RandomState,
+    }
+    impl BuildHasher for Padded {
+        type Hasher = DefaultHasher;
+        fn build_hasher(&self) -> DefaultHasher {
+            self.state.build_hasher()
+        }
+    }
+    let mut x: HashMap<u32, u8, Padded> = HashMap::default();
+    x.extend((0..100).map(|i| (i, 0)));
+    let mut ops = SizeOps::with_size_of_op(one_per_block);
+    assert_eq!(x.heap_size_of_children_with(&mut ops), 1);
+}
+
+#[cfg(target_pointer_width = "64")]
+#[test]
+fn test_btree() {
+    use std::collections::{BTreeMap, BTreeSet};
+
+    // No nodes.
+    let x: BTreeMap<u64, u64> = BTreeMap::new();
+    assert_eq!(x.heap_size_of_children(), 0);
+
+    // A single leaf: parent pointer, parent index and length, then 11 keys and 11 values.
+    let mut x = BTreeMap::new();
+    x.insert(0u64, 0u64);
+    assert_eq!(x.heap_size_of_children(), 192);
+
+    // The 12th key splits the leaf in two and adds an internal root, which is a leaf followed by
+    // 12 edges.
+    for i in 1..12 {
+        x.insert(i, i);
+    }
+    assert_eq!(x.heap_size_of_children(), 2 * 192 + (192 + 12 * 8));
+
+    // The values' own heap blocks are measured too.
+    let y: BTreeMap<u64, Box<u64>> = x.keys().map(|&i| (i, Box::new(i))).collect();
+    assert!(y.heap_size_of_children() >= x.heap_size_of_children() + 12 * 8);
+
+    // A set's leaves have no room for values.
+    let x: BTreeSet<u64> = (0..11).collect();
+    assert_eq!(x.heap_size_of_children(), 104);
+
+    // An emptied map keeps its root leaf, but without keys we can't tell it has one.
+    let mut x = BTreeMap::new();
+    x.insert(0u64, 0u32);
+    x.remove(&0);
+    assert_eq!(x.heap_size_of_children(), 0);
+}
+
+#[test]
+fn test_vec_deque() {
+    use std::collections::VecDeque;
+
+    // A deque made from a vector keeps the vector's buffer.
+    let x: Vec<i64> = Vec::with_capacity(8);
+    let buffer_size = x.heap_size_of_children();
+    let mut x = VecDeque::from(x);
+    x.extend(vec![0i64, 1, 2, 3]);
+    assert_eq!(x.heap_size_of_children(), buffer_size);
+
+    // The contents start partway into the buffer.
+    x.pop_front();
+    assert_eq!(x.as_slices().1.len(), 0);
+    assert_eq!(x.heap_size_of_children(), buffer_size);
+
+    // The contents wrap around the end of the buffer.
+    x.push_front(1);
+    x.push_front(0);
+    assert!(!x.as_slices().1.is_empty());
+    assert_eq!(x.heap_size_of_children(), buffer_size);
+
+    // The elements' own heap blocks are measured too.
+    let x: VecDeque<Box<i64>> = vec![Box::new(0i64), Box::new(1i64)].into();
+    assert!(x.heap_size_of_children() >= 2 * 8 + 2 * 8);
+}
+
+#[test]
+fn test_binary_heap() {
+    use std::collections::BinaryHeap;
+
+    // A heap made from a vector keeps the vector's buffer.
+    let x = vec![3i64, 1, 4, 1, 5, 9, 2, 6];
+    let buffer_size = x.heap_size_of_children();
+    let x = BinaryHeap::from(x);
+    assert_eq!(x.heap_size_of_children(), buffer_size);
+
+    let x: BinaryHeap<Box<i64>> = vec![Box::new(0i64), Box::new(1i64)].into();
+    assert!(x.heap_size_of_children() >= 2 * 8 + 2 * 8);
+}
+
+#[test]
+fn test_report() {
+    let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+    vec![Box::new(0i64)].report_heap_size("a/vec", &mut reporter);
+    Some(Box::new(String::from("x"))).report_heap_size("a/box", &mut reporter);
+    reporter.report("b", 10);
+    reporter.report("b", 5);
+    let (sender, _receiver) = std::sync::mpsc::channel::<Box<u8>>();
+    sender.report_heap_size("c/sender", &mut reporter);
+    let lock = std::sync::Mutex::new(Box::new(0u8));
+    let guard = lock.lock().unwrap();
+    lock.report_heap_size("c/lock", &mut reporter);
+    drop(guard);
+    let report = reporter.finish();
+
+    assert_eq!(report.size(), 2 + 2 + 15);
+    assert_eq!(report.get("a").unwrap().size(), 4);
+    assert_eq!(report.get("a").unwrap().own_size(), 0);
+    assert_eq!(report.get("a/box").unwrap().own_size(), 2);
+    assert_eq!(report.get("b").unwrap().size(), 15);
+    assert!(report.get("a/missing").is_none());
+
+    // Values that couldn't be measured are counted where they were reported.
+    assert_eq!(report.unmeasured(), 1);
+    assert_eq!(report.get("c/sender").unwrap().own_unmeasured(), 1);
+    assert_eq!(report.get("a").unwrap().unmeasured(), 0);
+    assert_eq!(report.contended(), 1);
+    assert_eq!(report.get("c/lock").unwrap().own_contended(), 1);
+    assert_eq!(report.get("c/sender").unwrap().contended(), 0);
+
+    let names: Vec<_> = report.children().iter().map(|child| child.name()).collect();
+    assert_eq!(names, ["a", "b", "c"]);
+
+    // Collections report the same total as they measure, at their own path.
+    fn assert_report_matches<T: HeapSizeOf>(x: &T) {
+        let mut reporter = Reporter::with_ops(SizeOps::with_size_of_op(one_per_block));
+        x.report_heap_size("x", &mut reporter);
+        let report = reporter.finish();
+        let size = x.heap_size_of_children_with(&mut SizeOps::with_size_of_op(one_per_block));
+        assert_eq!(report.get("x").unwrap().size(), size);
+        assert_eq!(report.size(), size);
+    }
+    let x: std::collections::HashMap<u32, Box<u8>> = (0..3).map(|i| (i, Box::new(0))).collect();
+    assert_report_matches(&x);
+    let x: std::collections::BTreeMap<u32, Box<u8>> = (0..3).map(|i| (i, Box::new(0))).collect();
+    assert_report_matches(&x);
+    let x: std::collections::VecDeque<Box<u8>> = (0..3).map(|_| Box::new(0)).collect();
+    assert_report_matches(&x);
+    assert_report_matches(&[Box::new(0u8), Box::new(1)]);
+    assert_report_matches(&Vec::<Box<u8>>::new());
+
+    assert_eq!(Reporter::join("", "a"), "a");
+    assert_eq!(Reporter::join("a", "b"), "a/b");
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "windows"))]
+#[test]
+fn test_over_aligned() {
+    // Blocks aligned beyond what the allocator guarantees anyway, which it may hand out
+    // differently (on Windows, `System` stores a pointer to the real block just before them).
+    #[allow(dead_code)]
+    #[derive(Clone)]
+    #[repr(align(32))]
+    struct Align32([u8; 32]);
+    #[allow(dead_code)]
+    #[repr(align(4096))]
+    struct AlignPage([u8; 64]);
+    impl HeapSizeOf for Align32 {
+        fn heap_size_of_children(&self) -> usize { 0 }
+    }
+    impl HeapSizeOf for AlignPage {
+        fn heap_size_of_children(&self) -> usize { 0 }
+    }
+
+    let x = assert_measurement_matches(|| Box::new(Align32([0; 32])));
+    assert!(x.heap_size_of_children() >= 32);
+    assert_measurement_matches(|| Box::new(AlignPage([0; 64])));
+    let x = assert_measurement_matches(|| vec![Align32([0; 32]); 1024 * 1024 / 32]);
+    assert!(x.heap_size_of_children() >= 1024 * 1024);
+
+    let x = Box::new(Align32([0; 32]));
+    unsafe {
+        assert_eq!(try_heap_size_of(&*x), Ok(heap_size_of(&*x)));
+    }
+}
+
+#[test]
+fn test_try_heap_size_of() {
+    static STATIC: [u64; 4] = [0; 4];
+    let local = [0u64; 4];
+    let x = Box::new(0u64);
+
+    unsafe {
+        assert_eq!(try_heap_size_of(&*x), Ok(heap_size_of(&*x)));
+        // Windows asks the heap, which only says the pointer isn't one of its blocks.
+        if cfg!(target_os = "windows") {
+            assert_eq!(try_heap_size_of(&local), Err(HeapSizeError::NotOwned));
+            assert_eq!(try_heap_size_of(&STATIC), Err(HeapSizeError::NotOwned));
+        } else {
+            assert_eq!(try_heap_size_of(&local), Err(HeapSizeError::Stack));
+            assert_eq!(try_heap_size_of(&STATIC), Err(HeapSizeError::Static));
+        }
+        assert_eq!(try_heap_size_of((&local as *const _ as *const u8).offset(1)),
+                   Err(HeapSizeError::Misaligned));
+        assert_eq!(try_heap_size_of::<u64>(::std::ptr::null()), Ok(0));
+    }
+}
+
+#[cfg(target_os = "windows")]
+#[test]
+fn test_windows_owns() {
+    use heapsize::{SystemProvider, UsableSizeProvider};
+
+    extern "system" {
+        fn VirtualAlloc(address: *mut c_void, size: usize, allocation_type: u32,
+                        protect: u32) -> *mut c_void;
+        fn VirtualFree(address: *mut c_void, size: usize, free_type: u32) -> i32;
+    }
+    const MEM_COMMIT_RESERVE: u32 = 0x3000;
+    const MEM_RELEASE: u32 = 0x8000;
+    const PAGE_READWRITE: u32 = 0x04;
+
+    #[allow(dead_code)]
+    #[repr(align(4096))]
+    struct AlignPage([u8; 64]);
+
+    let x = Box::new(0u64);
+    let aligned = Box::new(AlignPage([0; 64]));
+    unsafe {
+        assert_eq!(SystemProvider.owns(&*x as *const u64 as *const c_void), Some(true));
+        // Found through the pointer `System` stores just before over-aligned blocks.
+        let ptr = &*aligned as *const AlignPage as *const c_void;
+        assert_eq!(SystemProvider.owns(ptr), Some(true));
+        assert_eq!(try_heap_size_of(&*aligned), Ok(heap_size_of(&*aligned)));
+        assert!(heap_size_of(&*aligned) >= 64);
+
+        // Pages that aren't from the heap, with nothing mapped just before them, so the word
+        // before the pointer mustn't be read.
+        let page = VirtualAlloc(std::ptr::null_mut(), 4096, MEM_COMMIT_RESERVE, PAGE_READWRITE);
+        assert!(!page.is_null());
+        assert_eq!(SystemProvider.owns(page), Some(false));
+        assert_eq!(try_heap_size_of(page as *const u64), Err(HeapSizeError::NotOwned));
+        // The word before this one is readable and points to a heap block, but not one that
+        // contains it.
+        let inside = (page as *mut usize).offset(1);
+        *(page as *mut *const u64) = &*x;
+        assert_eq!(SystemProvider.owns(inside as *const c_void), Some(false));
+        assert!(VirtualFree(page, 0, MEM_RELEASE) != 0);
+    }
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+#[test]
+fn test_checked_size_ops() {
+    static NAME: [u64; 2] = [0; 2];
+
+    // A broken implementation that measures a buffer which isn't on the heap.
+    struct Interned(Vec<u64>, &'static [u64; 2]);
+
+    impl HeapSizeOf for Interned {
+        fn heap_size_of_children(&self) -> usize {
+            self.heap_size_of_children_with(&mut SizeOps::new())
+        }
+
+        fn heap_size_of_children_with(&self, ops: &mut SizeOps) -> usize {
+            self.0.heap_size_of_children_with(ops) + unsafe { ops.heap_size_of(self.1) }
+        }
+    }
+
+    let x = Interned(vec![0; 4], &NAME);
+    let mut ops = SizeOps::new().with_checks();
+    assert_eq!(x.heap_size_of_children_with(&mut ops),
+               vec![0u64; 4].heap_size_of_children());
+    assert_eq!(ops.offenders(), &[(&NAME as *const _ as usize, HeapSizeError::Static)]);
+
+    let mut ops = SizeOps::new();
+    x.0.heap_size_of_children_with(&mut ops);
+    assert!(ops.offenders().is_empty());
 }